@@ -0,0 +1,38 @@
+#!/bin/sh
+# Compares how long the rsync --copy-back and --copy-back-tar take to transfer a project's
+# target directory back, e.g. a deps-heavy one with many small files:
+#
+#     scripts/bench-copy-back.sh builder@buildbox path/to/project [runs]
+#
+# The times are the copy-back durations of --emit-metrics, so the upload and the (no-op)
+# build don't count. Every run copies back into an empty directory, so neither mode gets
+# to skip files that are already there.
+set -eu
+
+remote=$1
+project=$2
+runs=${3:-5}
+scratch=$(mktemp -d)
+trap 'rm -rf "$scratch"' EXIT
+
+cd "$project"
+# Build once up front, the runs below then only transfer the artifacts.
+cargo remote -r "$remote" build --release
+
+bench() {
+    mode=$1
+    shift
+    i=0
+    while [ "$i" -lt "$runs" ]; do
+        rm -rf "$scratch/$mode"
+        cargo remote -r "$remote" --copy-back --copy-back-root "$scratch/$mode" \
+            --emit-metrics "$scratch/$mode.prom" "$@" build --release >/dev/null 2>&1
+        awk '$1 == "remocom_copy_back_duration_seconds" { print $2 }' "$scratch/$mode.prom"
+        i=$((i + 1))
+    done | awk -v mode="$mode" '
+        { total += $1; if (NR == 1 || $1 < best) best = $1 }
+        END { printf "%-6s mean %.2fs, best %.2fs over %d runs\n", mode, total / NR, best, NR }'
+}
+
+bench rsync
+bench tar --copy-back-tar
@@ -1,33 +1,123 @@
 use log::{error, info, warn};
 use simple_logger::SimpleLogger;
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
     hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::{mpsc, Arc, Mutex, OnceLock},
     path::{Path, PathBuf},
-    process::{Command, exit, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::Deserialize;
 use structopt::StructOpt;
-use toml::Value;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "remocom", bin_name = "cargo")]
+#[allow(clippy::large_enum_variant)] // Parsed once at startup, the size doesn't matter.
 enum Opts {
     #[structopt(name = "remote")]
     Remote {
         #[structopt(
             short = "r",
             long = "remote", 
-            help = "Remote ssh build server")]
-        remote: Option<String>,
+            help = "Remote ssh build server. Given again, the next ones are fallbacks used in order: \
+                    when ssh can't connect during the upload or the build, the run starts over on the \
+                    next server",
+            number_of_values = 1)]
+        remote: Vec<String>,
+
+        #[structopt(
+            long = "config-profile",
+            help = "Use the remote and environment of this [profiles.<name>] section of the config",
+        )]
+        config_profile: Option<String>,
+
+        #[structopt(
+            long = "pool-strategy",
+            help = "How to pick a server from the configured pool when no other remote is given: \
+                    first or least-loaded",
+            default_value = "first",
+        )]
+        pool_strategy: PoolStrategy,
+
+        #[structopt(
+            long = "discover",
+            help = "Look for build servers advertised over mDNS (_remocom._tcp.local) when --remote isn't given",
+        )]
+        discover: bool,
+
+        #[structopt(
+            long = "interactive-remote",
+            help = "When --remote isn't given, choose the build server from a menu of the configured ones",
+        )]
+        interactive_remote: bool,
 
         #[structopt(
             short = "b",
             long = "build-env",
-            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. ",
+            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. \
+                    {build_path}, {workspace} and {target} (with --matrix-target) are replaced by \
+                    the remote build directory, the directory cargo runs in and the target triple. \
+                    KEY=keyring:<service>/<account> reads the value from the system keyring",
             default_value = "RUST_BACKTRACE=1",
         )]
         build_env: String,
 
+        #[structopt(
+            long = "env-set",
+            help = "Add the variables of this env_sets entry of the config to the build environment. \
+                    --build-env wins when both set a variable",
+        )]
+        env_set: Option<String>,
+
+        #[structopt(
+            long = "set-env",
+            help = "Pass KEY=VAL to the build through ssh's SetEnv (requires AcceptEnv on the server)",
+            number_of_values = 1,
+            parse(try_from_str = parse_env_assignment),
+        )]
+        set_env: Vec<String>,
+
+        #[structopt(
+            long = "unset-env",
+            help = "Unset this variable (e.g. one exported by the server's profile) before running cargo",
+            number_of_values = 1,
+            parse(try_from_str = parse_env_name),
+        )]
+        unset_env: Vec<String>,
+
+        #[structopt(
+            long = "rustc-wrapper",
+            help = "Compile through this wrapper on the build server (e.g. sccache). Overrides RUSTC_WRAPPER from --build-env",
+        )]
+        rustc_wrapper: Option<String>,
+
+        #[structopt(
+            long = "build-env-prefix",
+            help = "Log the names of the environment variables remocom adds to the build under this label",
+        )]
+        build_env_prefix: Option<String>,
+
+        #[structopt(
+            long = "redact",
+            help = "Never log the value of this build environment variable. Variables named like \
+                    *_TOKEN, *_SECRET or *_PASSWORD are always redacted",
+            number_of_values = 1,
+            parse(try_from_str = parse_env_name),
+        )]
+        redact: Vec<String>,
+
+        #[structopt(
+            long = "remote-env-file",
+            help = "Upload this file of shell variable assignments and source it before running cargo",
+            parse(from_os_str)
+        )]
+        remote_env_file: Option<PathBuf>,
+
         #[structopt(
             short = "d",
             long = "rustup-default",
@@ -36,6 +126,12 @@ enum Opts {
         )]
         rustup_default: String,
 
+        #[structopt(
+            long = "update-toolchain",
+            help = "Run `rustup update` on the build server for the toolchain(s) of the build before building",
+        )]
+        update_toolchain: bool,
+
         #[structopt(
             short = "e",
             long = "env",
@@ -47,16 +143,120 @@ enum Opts {
         #[structopt(
             short = "c",
             long = "copy-back",
-            help = "Transfers the target folder or file back to the local machine",
+            help = "Transfers the target folder or file back to the local machine. When building for a \
+                    --target, a --matrix-target or the detected host triple, the path is relative to \
+                    target/<triple>/",
         )] 
         copy_back: Option<Option<String>>,
 
+        #[structopt(
+            long = "copy-back-timeout",
+            help = "Abort the copy-back transfer if it takes longer than the given seconds",
+        )]
+        copy_back_timeout: Option<u64>,
+
+        #[structopt(
+            long = "copy-back-checksum",
+            help = "Compare artifacts by checksum during --copy-back so identical files are never transferred again",
+        )]
+        copy_back_checksum: bool,
+
+        #[structopt(
+            long = "copy-back-tar",
+            help = "Copy back as a single compressed tarball streamed over ssh, faster than rsync for \
+                    many small files. Unlike rsync, local files deleted remotely are kept",
+        )]
+        copy_back_tar: bool,
+
+        #[structopt(
+            long = "progress-format",
+            help = "How transfer progress is shown: human, or json to print {\"phase\":\"copy_back\",\"percent\":N} \
+                    lines on stderr during the copy-back",
+            default_value = "human",
+        )]
+        progress_format: ProgressFormat,
+
+        #[structopt(
+            long = "copy-back-root",
+            help = "Local directory --copy-back mirrors the remote target directory into [default: <project>/target]",
+            parse(from_os_str)
+        )]
+        copy_back_root: Option<PathBuf>,
+
+        #[structopt(
+            long = "link-dest",
+            help = "Hardlink the artifacts unchanged since an earlier --copy-back into this directory \
+                    (its --copy-back-root) instead of writing them again. Must be on the same file system",
+            parse(from_os_str)
+        )]
+        link_dest: Option<PathBuf>,
+
+        #[structopt(
+            long = "copy-back-as",
+            help = "Copy a single remote file to a local name, as <remote>:<local> relative to the project",
+            number_of_values = 1,
+            parse(try_from_str = parse_copy_back_mapping),
+        )]
+        copy_back_as: Vec<CopyBackMapping>,
+
+        #[structopt(
+            long = "copy-back-sources",
+            help = "After the build, transfer source files changed remotely (e.g. by cargo fmt) back to the local workspace",
+        )]
+        copy_back_sources: bool,
+
+        #[structopt(
+            long = "dry-build",
+            help = "Only check that the project compiles remotely: skip retrieving artifacts and report the result",
+        )]
+        dry_build: bool,
+
+        #[structopt(
+            long = "copy-back-tests",
+            help = "Copy the test executables built remotely (e.g. by `test --no-run`) back to the same place locally. \
+                    Builds with --message-format=json. With `nextest archive`, copies back the --archive-file",
+        )]
+        copy_back_tests: bool,
+
+        #[structopt(
+            long = "stream-artifacts",
+            help = "Experimental: copy each executable back as soon as cargo reports it built, \
+                    while the rest of the build goes on. Builds with --message-format=json",
+        )]
+        stream_artifacts: bool,
+
+        #[structopt(
+            long = "deploy",
+            help = "After a successful build, copy the built binary to this rsync destination (user@host:path)",
+            parse(try_from_str = parse_deploy_target),
+        )]
+        deploy: Option<String>,
+
+        #[structopt(
+            long = "fingerprint",
+            help = "Hash the built artifacts and compare them with an earlier build of the same git commit, \
+                    warning when they differ (a non-reproducible build)",
+        )]
+        fingerprint: bool,
+
+        #[structopt(
+            long = "force",
+            help = "Don't ask for confirmation before --copy-back-sources overwrites local files",
+        )]
+        force: bool,
+
         #[structopt(
             long = "no-copy-lock",
             help = "Do not transfer the Cargo.lock back to the local machine",
         )] 
         no_copy_lock: bool,
 
+        #[structopt(
+            long = "diff-lockfile",
+            help = "Report the crates added, removed or updated in the Cargo.lock copied back",
+        )]
+        diff_lockfile: bool,
+
         #[structopt(
             long = "manifest-path",
             help = "Path to the manifest to execute",
@@ -72,197 +272,5837 @@ enum Opts {
         )] 
         hidden: bool,
 
-        #[structopt(help = "cargo command that will be executed remotely")] 
-        command: String,
+        #[structopt(
+            long = "repo-root",
+            help = "Upload this directory instead of just the cargo workspace (e.g. a monorepo root)",
+            parse(from_os_str)
+        )]
+        repo_root: Option<PathBuf>,
 
         #[structopt(
-            help = "cargo options and flags that will be applied remotely",
-            name = "remote options",
-        )] 
-        options: Vec<String>,
-    },
-}
+            long = "sync-from-git-root",
+            help = "Upload the whole git repository containing the cargo workspace",
+            conflicts_with = "repo-root",
+        )]
+        sync_from_git_root: bool,
 
-/// Tries to parse the file. Logs warnings and return [`None`] if during reading or
-/// parsing errors occur. 
-/// Otherwise, returns [`Some(value)`].
-fn config_from_file(config_path: &Path) -> Option<Value> {
-    let config_file = std::fs::read_to_string(config_path)
-        .map_err(|e| {
-            warn!(
-                "Can't parse config file '{}' error(: {}",
-                config_path.to_string_lossy(),
-                e
-            );
-        })
-        .ok()?;
-    
-    let value = config_file
-        .parse::<Value>()
-        .map_err(|e| {
-            warn!(
-                "Can't parse config file '{}' error(: {}",
-                config_path.to_string_lossy(),
-                e
-            );
-        })
-        .ok()?;
-    
-        Some(value)
-}
+        #[structopt(
+            long = "inplace",
+            help = "Update files in place on the build server instead of writing a temporary copy. \
+                    Saves disk space, but an interrupted transfer leaves files half-written. \
+                    Pairs poorly with rsync's --partial, which then keeps those half-written files",
+        )]
+        inplace: bool,
 
-fn main() {
-    SimpleLogger::new().init().unwrap();
-    info!("Log set");
+        #[structopt(
+            long = "normalize-times",
+            help = "Don't preserve modification times on upload, so changed files are stamped with the transfer time. \
+                    Files are compared by checksum instead, which reads every file on both ends",
+        )]
+        normalize_times: bool,
 
-    let Opts::Remote {
-        remote,
-        build_env,
-        rustup_default,
-        env,
-        copy_back,
-        no_copy_lock,
-        manifest_path,
-        hidden,
-        command,
-        options,
-    } = Opts::from_args();
+        #[structopt(
+            long = "compress-min-size",
+            help = "Only compress the upload when at least this many bytes need to be transferred",
+        )]
+        compress_min_size: Option<u64>,
 
-    let mut cli_metadata = cargo_metadata::MetadataCommand::new();
-    cli_metadata.manifest_path(manifest_path).no_deps();
+        #[structopt(
+            long = "max-file-size",
+            help = "Don't upload files larger than this many bytes (e.g. stray datasets), listing the ones skipped",
+        )]
+        max_file_size: Option<u64>,
 
-    let project_metadata = cli_metadata.exec().unwrap();
-    let project_dir = project_metadata.workspace_root;
+        #[structopt(
+            long = "report-savings",
+            help = "Report how much of the uploaded tree the incremental transfer actually had to send",
+        )]
+        report_savings: bool,
 
-    let config_options = vec![
-        config_from_file(&project_dir.join("remocom-config.toml")),
-        xdg::BaseDirectories::with_prefix("remocom")
-            .ok()
-            .and_then(|base| base.find_config_file("remocom-config.toml"))
-            .and_then(|p: PathBuf| config_from_file(&p)),
-    ];
+        #[structopt(
+            long = "parallel-upload",
+            help = "Split the top-level directories among this many concurrent rsync transfers",
+            default_value = "1",
+        )]
+        parallel_upload: usize,
 
-    let build_server = remote
-        .or_else(|| {
-            config_options 
-                .into_iter()
-                .flat_map(|config| config.and_then(|c| c["remote"].as_str().map(String::from)))
-                .next()
-    })
-    .unwrap_or_else(|| {
-        error!("No remote server defined (use remcom-config or --remote flag)");
-        exit(-3);
-    });
+        #[structopt(
+            long = "transport-command",
+            help = "Upload with this shell command instead of rsync, e.g. a unison or mutagen invocation. \
+                    {source}, {dest} (server:build path), {server} and {build_path} are replaced. \
+                    The command alone is responsible for the remote copy being complete and up to date",
+            parse(try_from_str = parse_transport_command),
+        )]
+        transport_command: Option<String>,
 
-    // This is a unique build path created using the project's hashed dir name.
-    let mut hasher = DefaultHasher::new();
-    project_dir.hash(&mut hasher);
-    let build_path = format!("~/remote-builds/{}/", hasher.finish());
+        #[structopt(
+            long = "toolchain-file",
+            help = "Upload the given file as rust-toolchain.toml for this build only (skips rustup default)",
+            parse(from_os_str)
+        )]
+        toolchain_file: Option<PathBuf>,
 
-    info!("Sources are being transferred to your build server.");
-    // Transfers the project to the user's build server
-    let mut rsync_to = Command::new("rsync");
+        #[structopt(
+            long = "toolchain-filename",
+            help = "Name of the project's toolchain file, for setups using another one than rust-toolchain \
+                    or rust-toolchain.toml. --toolchain-file is uploaded under this name",
+        )]
+        toolchain_filename: Option<String>,
 
-    rsync_to
-        .arg("-a".to_owned())
-        .arg("--delete")
-        .arg("--compress")
-        .arg("--info=progress2")
-        .arg("--exclude")
-        .arg("--target");
-    
-    if !hidden {
-        rsync_to.arg("--exclude").arg(".*");
-    }
+        #[structopt(
+            long = "setup-script",
+            help = "Run this local script on the build server once, the first time the build directory is used",
+            parse(from_os_str)
+        )]
+        setup_script: Option<PathBuf>,
 
-    rsync_to
-        .arg("--rsync-path")
-        .arg("mkdir -p remote-builds && rsync")
-        .arg(format!("{}/", project_dir.to_string_lossy()))
-        .arg(format!("{}:{}", build_server, build_path))
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to transfer project to build server (error: {})", e);
-            exit(-4);
-        });
-    
-    log::info!("Build ENV: {:?}", build_env);
-    log::info!("Environment profile: {:?}", env);
-    log::info!("Build path: {:?}", build_path);
+        #[structopt(
+            long = "ping-first",
+            help = "Check that the build server is reachable before transferring sources",
+        )]
+        ping_first: bool,
 
-    let build_command = format!(
-        "source {}; rustup default {}; cd {}; {} cargo {} {}",
-        env,
-        rustup_default,
-        build_path,
-        build_env,
-        command,
-        options.join(" ")
-    );
-
-    info!("Starting build process...");
-    let output = Command::new("ssh")
-        .arg("-t")
-        .arg(&build_server)
-        .arg(build_command)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to run cargo command remotely (error: {})", e);
-            exit(-5);
-        });
-    
-    if let Some(file_name) = copy_back {
-        log::info!("Transferring artifacts back to client");
-        let file_name = file_name.unwrap_or_else(String::new);
-        Command::new("rsync")
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("--info=progress2")
-            .arg(format!("{}:{}/target/{}", build_server, build_path, file_name))
-            .arg(format!("{}/target/{}", project_dir.to_string_lossy(), file_name))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                log::error!(
-                    "Failed to transfer target back to local machine (error: {})",
-                    e
-                );
-                exit(-6);
-            });
-    }
+        #[structopt(
+            long = "check-clock",
+            help = "Before transferring sources, warn if the build server's clock is off from the local one, \
+                    which confuses rsync's modification time comparisons",
+        )]
+        check_clock: bool,
 
-    if !no_copy_lock {
-        log::info!("Transferring Cargo.lock file back to the client");
-        Command::new("rsync")
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("--info=progress2")
-            .arg(format!("{}:{}/Cargo.lock", build_server, build_path))
-            .arg(format!("{}/Cargo.lock", project_dir.to_string_lossy()))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                log::error!(
-                    "Failed to transfer Cargo.lock back to local machine (error: {})",
-                    e
-                );
-                exit(-7);
-            });
-    }
+        #[structopt(
+            long = "remote-build-dir-lock-timeout",
+            help = "Lock the remote build directory so concurrent runs don't share it, \
+                    waiting up to this many seconds for another run to finish",
+        )]
+        remote_build_dir_lock_timeout: Option<u64>,
 
-    if !output.status.success() {
-        exit(output.status.code().unwrap_or(1))
+        #[structopt(
+            long = "stale-lock-after",
+            help = "Remove a build directory lock older than this many seconds, left behind by a crashed run",
+            requires = "remote-build-dir-lock-timeout",
+        )]
+        stale_lock_after: Option<u64>,
+
+        #[structopt(
+            long = "auto-gc",
+            help = "Before building, delete the least recently used directories in ~/remote-builds \
+                    while the server has less free disk space than --auto-gc-min-free",
+        )]
+        auto_gc: bool,
+
+        #[structopt(
+            long = "auto-gc-min-free",
+            help = "Free disk space in GiB that --auto-gc makes room for",
+            default_value = "10",
+        )]
+        auto_gc_min_free: u64,
+
+        #[structopt(
+            long = "preflight-ttl",
+            help = "Skip preflight checks (--ping-first, remote tools) that passed on this server within this many seconds",
+            default_value = "300",
+        )]
+        preflight_ttl: u64,
+
+        #[structopt(
+            long = "no-preflight-cache",
+            help = "Always run the preflight checks, ignoring and not recording earlier results",
+        )]
+        no_preflight_cache: bool,
+
+        #[structopt(
+            long = "control-master",
+            help = "Share one multiplexed ssh connection between all transfers and commands",
+        )]
+        control_master: bool,
+
+        #[structopt(
+            long = "control-persist",
+            help = "How long the multiplexed connection lingers after last use (e.g. 30s, 10m). Implies --control-master",
+            parse(try_from_str = parse_ssh_duration),
+        )]
+        control_persist: Option<String>,
+
+        #[structopt(
+            long = "control-path",
+            help = "Reuse the multiplexed connection of this existing ssh ControlMaster socket instead of opening one",
+            conflicts_with_all = &["control-master", "control-persist"],
+            parse(from_os_str)
+        )]
+        control_path: Option<PathBuf>,
+
+        #[structopt(
+            long = "ssh-proxy-command",
+            help = "Connect to the build server through this command (ssh's ProxyCommand), e.g. socat to a unix socket",
+        )]
+        ssh_proxy_command: Option<String>,
+
+        #[structopt(
+            long = "known-hosts",
+            help = "Trust build server host keys from this known_hosts file only, adding unknown hosts on first use",
+            parse(from_os_str)
+        )]
+        known_hosts: Option<PathBuf>,
+
+        #[structopt(
+            long = "strict-host-keys",
+            help = "With --known-hosts, refuse hosts that aren't already in the file instead of adding them",
+            requires = "known-hosts",
+        )]
+        strict_host_keys: bool,
+
+        #[structopt(
+            long = "ssh-verbose",
+            help = "Trace ssh's connection setup, key exchange, ciphers and compression (ssh -vvv) \
+                    into the --ssh-debug-log file, keeping it out of the build output",
+        )]
+        ssh_verbose: bool,
+
+        #[structopt(
+            long = "ssh-debug-log",
+            help = "File the --ssh-verbose trace of every ssh connection is written to \
+                    [default: remocom-ssh-debug.log in the temporary directory]",
+            requires = "ssh-verbose",
+            parse(from_os_str)
+        )]
+        ssh_debug_log: Option<PathBuf>,
+
+        #[structopt(
+            long = "emit-connect-script",
+            help = "Write an executable script that opens a shell on the build server in the build directory, \
+                    with the same ssh options",
+            parse(from_os_str)
+        )]
+        emit_connect_script: Option<PathBuf>,
+
+        #[structopt(
+            long = "per-branch",
+            help = "Use a separate remote build directory for each git branch",
+        )]
+        per_branch: bool,
+
+        #[structopt(
+            long = "abort-if-dirty",
+            help = "Refuse to build when the git working tree has uncommitted changes",
+        )]
+        abort_if_dirty: bool,
+
+        #[structopt(
+            long = "allow-dirty",
+            help = "Build even with uncommitted changes, overriding --abort-if-dirty",
+        )]
+        allow_dirty: bool,
+
+        #[structopt(
+            long = "dir-naming",
+            help = "How to name the remote build directory: an opaque hash, or the package name plus a short hash",
+            possible_values = &["hash", "slug"],
+            default_value = "hash",
+        )]
+        dir_naming: DirNaming,
+
+        #[structopt(
+            long = "namespace",
+            help = "Mixed into the remote build directory name so people sharing an account on the \
+                    build server don't share build directories [default: the local $USER]",
+        )]
+        namespace: Option<String>,
+
+        #[structopt(
+            long = "build-dir",
+            help = "Build in this directory on the server (absolute or ~/...) instead of one named after the project",
+            conflicts_with_all = &["per-branch", "namespace"],
+            parse(try_from_str = parse_remote_build_dir),
+        )]
+        remote_build_dir: Option<String>,
+
+        #[structopt(
+            long = "workdir",
+            help = "Run the remote command in this directory, relative to the uploaded root, instead of the workspace",
+            parse(try_from_str = parse_workdir),
+        )]
+        workdir: Option<String>,
+
+        #[structopt(
+            long = "skip-unchanged",
+            help = "Skip the build when the upload didn't change any file on the build server \
+                    and the last build there succeeded",
+        )]
+        skip_unchanged: bool,
+
+        #[structopt(
+            long = "build-log",
+            help = "Keep the full build output in a log on the server and copy it to this path if the build fails",
+            parse(from_os_str)
+        )]
+        build_log: Option<PathBuf>,
+
+        #[structopt(
+            long = "stdin-file",
+            help = "Feed this file to the remote command's stdin instead of the terminal",
+            parse(from_os_str)
+        )]
+        stdin_file: Option<PathBuf>,
+
+        #[structopt(
+            long = "rebuild-retries",
+            help = "Run the remote cargo command again up to this many times if it fails (e.g. flaky tests)",
+            default_value = "0",
+        )]
+        rebuild_retries: u32,
+
+        #[structopt(
+            long = "resync-between-retries",
+            help = "Transfer the sources again before each --rebuild-retries attempt",
+        )]
+        resync_between_retries: bool,
+
+        #[structopt(
+            long = "retry-if-output-matches",
+            help = "Only use the --rebuild-retries when the failed build's output matches this regex, \
+                    e.g. a known flaky test error",
+        )]
+        retry_if_output_matches: Option<regex::Regex>,
+
+        #[structopt(
+            long = "oom-retry",
+            help = "When the build looks killed for lack of memory (SIGKILL, exit status 137), \
+                    run it again with half the cargo jobs, down to one",
+        )]
+        oom_retry: bool,
+
+        #[structopt(
+            long = "output-prefix",
+            help = "Prefix every line of the remote build output with this tag, e.g. [buildbox]",
+        )]
+        output_prefix: Option<String>,
+
+        #[structopt(
+            long = "annotations",
+            help = "Turn compiler errors and warnings into CI annotations (github). Builds with --message-format=json",
+        )]
+        annotations: Option<AnnotationFormat>,
+
+        #[structopt(
+            long = "quiet-build",
+            help = "Hide cargo's progress (Compiling, Checking, ...) and show only compiler warnings, \
+                    errors and the build result. Builds with --message-format=json",
+        )]
+        quiet_build: bool,
+
+        #[structopt(
+            long = "record",
+            help = "Record the output of the remote build to this asciicast v2 file, for replaying with asciinema",
+            parse(from_os_str)
+        )]
+        record: Option<PathBuf>,
+
+        #[structopt(
+            long = "build-graph",
+            help = "Save cargo's unit graph of the remote command (--unit-graph, nightly only) to this JSON file",
+            parse(from_os_str)
+        )]
+        build_graph: Option<PathBuf>,
+
+        #[structopt(
+            long = "stream-to",
+            help = "Also send every line of the remote build output to this websocket (ws://host:port/path), \
+                    as {\"stream\":\"stdout\"|\"stderr\",\"line\":...} JSON messages",
+        )]
+        stream_to: Option<String>,
+
+        #[structopt(
+            long = "stall-warn",
+            help = "Warn each time the remote build has printed nothing for this many seconds, without stopping it",
+        )]
+        stall_warn: Option<u64>,
+
+        #[structopt(
+            long = "heartbeat",
+            help = "Print a \"still building\" line every this many seconds during the build, \
+                    so CI systems that kill silent jobs don't stop a long link step",
+        )]
+        heartbeat: Option<u64>,
+
+        #[structopt(
+            long = "always-save-log",
+            help = "Copy the --build-log back even when the build succeeds",
+            requires = "build-log",
+        )]
+        always_save_log: bool,
+
+        #[structopt(
+            long = "strip-ansi",
+            help = "Remove colors and other terminal escape sequences from the --build-log copied back. \
+                    The live output keeps them",
+            requires = "build-log",
+        )]
+        strip_ansi: bool,
+
+        #[structopt(
+            long = "emit-metrics",
+            help = "Write run metrics to a Prometheus textfile path, or send them to a statsd host:port",
+        )]
+        emit_metrics: Option<String>,
+
+        #[structopt(
+            long = "exit-code-map",
+            help = "Exit with other codes than remocom's, as comma-separated internal:external pairs. \
+                    Internal codes are the negative ones remocom uses (or as the shell sees them, e.g. 252 for -4), \
+                    give them with = (--exit-code-map=-4:75)",
+            parse(try_from_str = parse_exit_code_map),
+        )]
+        exit_code_map: Option<HashMap<i32, i32>>,
+
+        #[structopt(
+            long = "systemd-run",
+            help = "Run the remote cargo command in a systemd-run scope with resource limits",
+        )]
+        systemd_run: bool,
+
+        #[structopt(
+            long = "memory-max",
+            help = "MemoryMax limit for --systemd-run (e.g. 8G)",
+        )]
+        memory_max: Option<String>,
+
+        #[structopt(
+            long = "cpu-quota",
+            help = "CPUQuota limit for --systemd-run (e.g. 200%)",
+        )]
+        cpu_quota: Option<String>,
+
+        #[structopt(
+            long = "cgroup",
+            help = "Run the remote build inside this cgroup v2 directory (e.g. /sys/fs/cgroup/builds/team-a)",
+        )]
+        cgroup: Option<String>,
+
+        #[structopt(
+            long = "run-as",
+            help = "Run the remote cargo command as this user through sudo (requires passwordless sudo)",
+        )]
+        run_as: Option<String>,
+
+        #[structopt(
+            long = "jobs-fraction",
+            help = "Build with this fraction (0.0-1.0] of the build server's CPUs as cargo jobs, at least one",
+            parse(try_from_str = parse_jobs_fraction),
+        )]
+        jobs_fraction: Option<f64>,
+
+        #[structopt(
+            long = "matrix-toolchain",
+            help = "Build once per toolchain in this comma-separated list (e.g. stable,nightly)",
+            use_delimiter = true,
+            number_of_values = 1,
+        )]
+        matrix_toolchain: Vec<String>,
+
+        #[structopt(
+            long = "matrix-target",
+            help = "Build once per target in this comma-separated list, combined with --matrix-toolchain",
+            use_delimiter = true,
+            number_of_values = 1,
+        )]
+        matrix_target: Vec<String>,
+
+        #[structopt(
+            long = "detect-host-triple",
+            help = "Build for the build server's exact host triple (from rustc -vV) and copy back from its target directory",
+        )]
+        detect_host_triple: bool,
+
+        #[structopt(
+            long = "use-cross",
+            help = "Build with cross instead of cargo on the build server (needs cross and Docker there), \
+                    to cross-compile for the --target given in the cargo options",
+        )]
+        use_cross: bool,
+
+        #[structopt(
+            long = "watch",
+            help = "Keep running and start a new build whenever a file in the uploaded directory changes",
+        )]
+        watch: bool,
+
+        #[structopt(
+            long = "watch-debounce",
+            help = "With --watch, wait until no file changed for this many milliseconds before rebuilding",
+            default_value = "300",
+        )]
+        watch_debounce: u64,
+
+        #[structopt(
+            long = "watch-clear",
+            help = "With --watch, clear the terminal before each build",
+        )]
+        watch_clear: bool,
+
+        #[structopt(
+            long = "warm-index",
+            help = "Only upload the manifests and run `cargo fetch` on the build server, \
+                    so its registry index and dependencies are ready for later builds",
+        )]
+        warm_index: bool,
+
+        #[structopt(
+            help = "cargo command that will be executed remotely [default: default_command from the config]",
+        )] 
+        command: Option<String>,
+
+        #[structopt(
+            help = "cargo options and flags that will be applied remotely",
+            name = "remote options",
+        )] 
+        options: Vec<String>,
+    },
+
+    /// Lists every build server defined in the config files.
+    #[structopt(name = "list-remotes")]
+    ListRemotes {
+        #[structopt(
+            long = "manifest-path",
+            help = "Path to the manifest of the project whose config is read",
+            default_value = "Cargo.toml",
+            parse(from_os_str)
+        )]
+        manifest_path: PathBuf,
+    },
+
+    /// Checks the config files for errors without building anything.
+    #[structopt(name = "check-config")]
+    CheckConfig {
+        #[structopt(
+            long = "manifest-path",
+            help = "Path to the manifest of the project whose config is checked",
+            default_value = "Cargo.toml",
+            parse(from_os_str)
+        )]
+        manifest_path: PathBuf,
+    },
+}
+
+/// The `--exit-code-map` applied by [`exit`].
+static EXIT_CODE_MAP: OnceLock<HashMap<i32, i32>> = OnceLock::new();
+
+/// Cleanups of the build server's state, run by [`exit`] and [`run_cleanups`].
+static CLEANUPS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+/// Registers `cleanup` to run however the run ends, e.g. to release the build directory lock.
+fn at_exit(cleanup: impl FnOnce() + Send + 'static) {
+    CLEANUPS.lock().unwrap().push(Box::new(cleanup));
+}
+
+/// Runs the cleanups registered with [`at_exit`], the latest first.
+fn run_cleanups() {
+    let cleanups = std::mem::take(&mut *CLEANUPS.lock().unwrap());
+    for cleanup in cleanups.into_iter().rev() {
+        cleanup();
+    }
+}
+
+/// Exits the process with `code`, or the code `--exit-code-map` maps it to, after running
+/// the cleanups and writing the metrics. Every exit of remocom goes through here.
+fn exit(code: i32) -> ! {
+    run_cleanups();
+    let code = EXIT_CODE_MAP
+        .get()
+        .and_then(|map| map.get(&code).or_else(|| map.get(&(code & 0xff))))
+        .copied()
+        .unwrap_or(code);
+    flush_metrics(code);
+    std::process::exit(code)
+}
+
+fn parse_exit_code_map(value: &str) -> Result<HashMap<i32, i32>, String> {
+    value
+        .split(',')
+        .map(|pair| {
+            let codes = pair.split_once(':').and_then(|(internal, external)| {
+                Some((internal.trim().parse().ok()?, external.trim().parse().ok()?))
+            });
+            codes.ok_or_else(|| format!("invalid exit code mapping '{}' (expected internal:external)", pair))
+        })
+        .collect()
+}
+
+/// How the remote build directory under `~/remote-builds/` is named.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirNaming {
+    /// The project hash alone, e.g. `1234567890123456789`.
+    Hash,
+    /// The package name and a short hash, e.g. `myapp-a1b2c3`.
+    Slug,
+}
+
+impl std::str::FromStr for DirNaming {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hash" => Ok(DirNaming::Hash),
+            "slug" => Ok(DirNaming::Slug),
+            _ => Err(format!("unknown directory naming '{}' (expected hash or slug)", value)),
+        }
+    }
+}
+
+/// Turns `name` into something safe and readable as a directory name: lowercase ASCII
+/// alphanumerics separated by single dashes.
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+/// The unique build path of the project uploaded from `sync_root`, named after its hash
+/// with the `namespace` and the `branch` mixed in, see [`DirNaming`].
+fn default_build_path(
+    sync_root: &Path,
+    namespace: &str,
+    branch: Option<&str>,
+    dir_naming: DirNaming,
+    package_name: &str,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    sync_root.hash(&mut hasher);
+    if !namespace.is_empty() {
+        namespace.hash(&mut hasher);
+    }
+    if let Some(branch) = branch {
+        branch.hash(&mut hasher);
+    }
+    let build_dir = match dir_naming {
+        DirNaming::Hash => hasher.finish().to_string(),
+        DirNaming::Slug => {
+            let short_hash = format!("{:06x}", hasher.finish() & 0xff_ffff);
+            match slugify(package_name) {
+                slug if slug.is_empty() => short_hash,
+                slug => format!("{}-{}", slug, short_hash),
+            }
+        }
+    };
+    format!("~/remote-builds/{}/", build_dir)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AnnotationFormat {
+    /// GitHub Actions workflow commands, e.g. `::error file=src/main.rs,line=3::...`.
+    Github,
+}
+
+impl std::str::FromStr for AnnotationFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "github" => Ok(AnnotationFormat::Github),
+            _ => Err(format!("unknown annotation format '{}' (expected github)", value)),
+        }
+    }
+}
+
+/// Escapes the message of a GitHub workflow command.
+fn escape_workflow_data(value: &str) -> String {
+    value.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escapes a property (`file=...`) of a GitHub workflow command.
+fn escape_workflow_property(value: &str) -> String {
+    escape_workflow_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// The GitHub workflow command annotating the primary span of `diagnostic`. Paths are
+/// relative to the workspace, so `project_rel_path` makes them relative to the repository.
+fn github_annotation(diagnostic: &cargo_metadata::diagnostic::Diagnostic, project_rel_path: &Path) -> Option<String> {
+    use cargo_metadata::diagnostic::DiagnosticLevel;
+
+    let command = match diagnostic.level {
+        DiagnosticLevel::Ice | DiagnosticLevel::Error => "error",
+        DiagnosticLevel::Warning => "warning",
+        DiagnosticLevel::Note | DiagnosticLevel::Help => "notice",
+        _ => return None,
+    };
+    // Summaries like "aborting due to previous error" don't point anywhere.
+    let span = diagnostic.spans.iter().find(|span| span.is_primary)?;
+
+    let mut properties = Vec::new();
+    if Path::new(&span.file_name).is_relative() {
+        let file = project_rel_path.join(&span.file_name);
+        properties.push(format!("file={}", escape_workflow_property(&file.to_string_lossy())));
+    }
+    properties.push(format!("line={}", span.line_start));
+    properties.push(format!("endLine={}", span.line_end));
+    properties.push(format!("col={}", span.column_start));
+    properties.push(format!("endColumn={}", span.column_end));
+    properties.push(format!("title={}", escape_workflow_property(&diagnostic.message)));
+
+    let message = diagnostic.rendered.as_deref().unwrap_or(&diagnostic.message);
+    Some(format!("::{} {}::{}", command, properties.join(","), escape_workflow_data(message.trim_end())))
+}
+
+/// Whether `line` is one of cargo's progress messages, e.g. `   Compiling foo v0.1.0` or the
+/// `Building [===>   ] 3/10` bar. Only the text after the last carriage return is looked at,
+/// that's what the terminal ends up showing.
+fn is_cargo_progress(line: &str) -> bool {
+    let line = strip_ansi_escapes(line);
+    let shown = line.rsplit('\r').next().unwrap_or_default();
+    matches!(
+        shown.split_whitespace().next(),
+        Some("Compiling")
+            | Some("Checking")
+            | Some("Documenting")
+            | Some("Building")
+            | Some("Fresh")
+            | Some("Updating")
+            | Some("Locking")
+            | Some("Adding")
+            | Some("Downloading")
+            | Some("Downloaded")
+            | Some("Blocking")
+    )
+}
+
+/// Where the remote file at the absolute `remote_path` goes locally: the same place relative
+/// to the upload root if it's inside the build directory, the local `target` directory otherwise
+/// (e.g. with a `CARGO_TARGET_DIR` outside the build directory).
+fn local_path_of_remote(remote_path: &str, build_path: &str, sync_root: &Path, project_dir: &Path) -> PathBuf {
+    // The home directory isn't known here, `~/dir/` is matched as `/dir/`.
+    match remote_path.split_once(build_path.trim_start_matches('~')) {
+        Some((_, rel_path)) => sync_root.join(rel_path.trim_start_matches('/')),
+        None => {
+            let file_name = Path::new(remote_path).file_name().unwrap_or_default();
+            project_dir.join("target").join(file_name)
+        }
+    }
+}
+
+/// A `--copy-back-as` mapping of a remote file to a local destination.
+#[derive(Debug, Clone)]
+struct CopyBackMapping {
+    remote: String,
+    local: PathBuf,
+}
+
+fn parse_copy_back_mapping(value: &str) -> Result<CopyBackMapping, String> {
+    match value.split_once(':') {
+        Some((remote, local)) if !remote.is_empty() && !local.is_empty() => Ok(CopyBackMapping {
+            remote: remote.to_owned(),
+            local: PathBuf::from(local),
+        }),
+        _ => Err(format!("invalid mapping '{}' (expected <remote>:<local>)", value)),
+    }
+}
+
+/// The parts of a `Cargo.lock` needed to compare two of them.
+#[derive(Debug, Default, Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+/// Describes how the locked crates changed between two `Cargo.lock` contents, one line
+/// per crate: `+ name version`, `- name version` or `~ name old -> new`.
+fn diff_lockfiles(old: &str, new: &str) -> Vec<String> {
+    let versions = |lock: &str| {
+        let mut versions: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for package in toml::from_str::<Lockfile>(lock).unwrap_or_default().package {
+            versions.entry(package.name).or_default().insert(package.version);
+        }
+        versions
+    };
+    let old = versions(old);
+    let new = versions(new);
+    let empty = BTreeSet::new();
+
+    let names: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    let mut changes = Vec::new();
+    for name in names {
+        let old_versions = old.get(name).unwrap_or(&empty);
+        let new_versions = new.get(name).unwrap_or(&empty);
+        let removed: Vec<_> = old_versions.difference(new_versions).collect();
+        let added: Vec<_> = new_versions.difference(old_versions).collect();
+
+        if let ([from], [to]) = (removed.as_slice(), added.as_slice()) {
+            changes.push(format!("~ {} {} -> {}", name, from, to));
+            continue;
+        }
+        changes.extend(removed.iter().map(|version| format!("- {} {}", name, version)));
+        changes.extend(added.iter().map(|version| format!("+ {} {}", name, version)));
+    }
+    changes
+}
+
+/// Timings and counters gathered during a run, for `--emit-metrics`.
+#[derive(Debug, Default)]
+struct Metrics {
+    upload_duration: Option<Duration>,
+    upload_bytes: Option<u64>,
+    build_duration: Option<Duration>,
+    copy_back_duration: Option<Duration>,
+    success: bool,
+    exit_code: i32,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus textfile collector format.
+    fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut gauge = |name: &str, help: &str, value: String| {
+            text.push_str(&format!(
+                "# HELP remocom_{0} {1}\n# TYPE remocom_{0} gauge\nremocom_{0} {2}\n",
+                name, help, value
+            ));
+        };
+
+        if let Some(d) = self.upload_duration {
+            gauge("upload_duration_seconds", "Time spent transferring sources", d.as_secs_f64().to_string());
+        }
+        if let Some(bytes) = self.upload_bytes {
+            gauge("upload_bytes", "Bytes sent while transferring sources", bytes.to_string());
+        }
+        if let Some(d) = self.build_duration {
+            gauge("build_duration_seconds", "Time spent running the remote cargo command", d.as_secs_f64().to_string());
+        }
+        if let Some(d) = self.copy_back_duration {
+            gauge("copy_back_duration_seconds", "Time spent transferring artifacts back", d.as_secs_f64().to_string());
+        }
+        gauge("success", "Whether the remote cargo command succeeded", (self.success as u8).to_string());
+        gauge("exit_code", "The code remocom exited with", self.exit_code.to_string());
+        text
+    }
+
+    /// Renders the metrics as statsd lines.
+    fn to_statsd(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(d) = self.upload_duration {
+            lines.push(format!("remocom.upload_duration:{}|ms", d.as_millis()));
+        }
+        if let Some(bytes) = self.upload_bytes {
+            lines.push(format!("remocom.upload_bytes:{}|g", bytes));
+        }
+        if let Some(d) = self.build_duration {
+            lines.push(format!("remocom.build_duration:{}|ms", d.as_millis()));
+        }
+        if let Some(d) = self.copy_back_duration {
+            lines.push(format!("remocom.copy_back_duration:{}|ms", d.as_millis()));
+        }
+        let outcome = if self.success { "succeeded" } else { "failed" };
+        lines.push(format!("remocom.builds_{}:1|c", outcome));
+        lines.push(format!("remocom.exit_code:{}|g", self.exit_code));
+        lines
+    }
+}
+
+/// The `--emit-metrics` target and the metrics gathered so far, written as remocom exits.
+static METRICS: Mutex<Option<(String, Metrics)>> = Mutex::new(None);
+
+/// Records a measurement for `--emit-metrics`, if it was given.
+fn update_metrics(update: impl FnOnce(&mut Metrics)) {
+    if let Some((_, metrics)) = METRICS.lock().unwrap().as_mut() {
+        update(metrics);
+    }
+}
+
+/// Writes the metrics gathered so far along with the code remocom exits with. Only the
+/// first call writes them, so a run reports exactly once however it ends.
+fn flush_metrics(exit_code: i32) {
+    let pending = METRICS.lock().unwrap().take();
+    if let Some((target, mut metrics)) = pending {
+        metrics.exit_code = exit_code;
+        write_metrics(&target, &metrics);
+    }
+}
+
+/// Sends `metrics` to `target`: a statsd endpoint if it parses as a socket address,
+/// otherwise a Prometheus textfile path. Failures are only logged, they never fail the build.
+fn write_metrics(target: &str, metrics: &Metrics) {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" });
+        let sent = socket.and_then(|socket| {
+            metrics
+                .to_statsd()
+                .iter()
+                .try_for_each(|line| socket.send_to(line.as_bytes(), addr).map(|_| ()))
+        });
+        if let Err(e) = sent {
+            warn!("Failed to send metrics to '{}' (error: {})", target, e);
+        }
+        return;
+    }
+
+    // Write to a temporary file first so collectors never read a partial file.
+    let path = Path::new(target);
+    let tmp_path = path.with_extension("prom.tmp");
+    let written = std::fs::write(&tmp_path, metrics.to_prometheus())
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if let Err(e) = written {
+        warn!("Failed to write metrics to '{}' (error: {})", target, e);
+    }
+}
+
+/// Copies everything from `reader` to our stdout as it arrives, returning a copy of it.
+fn forward_and_capture(mut reader: impl Read) -> std::io::Result<String> {
+    let mut stdout = std::io::stdout();
+    let mut captured = Vec::new();
+    let mut buf = [0; 8192];
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        stdout.write_all(&buf[..read])?;
+        stdout.flush()?;
+        captured.extend_from_slice(&buf[..read]);
+    }
+
+    Ok(String::from_utf8_lossy(&captured).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProgressFormat {
+    /// rsync's own progress output.
+    Human,
+    /// One JSON object per progress update on stderr, for programs wrapping remocom.
+    Json,
+}
+
+impl std::str::FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "human" => Ok(ProgressFormat::Human),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format '{}' (expected human or json)", value)),
+        }
+    }
+}
+
+/// The percentage of an rsync `--info=progress2` update, e.g.
+/// `  1,234,567  45%  1.23MB/s    0:00:01 (xfr#1, to-chk=0/3)`.
+fn parse_progress_percent(update: &str) -> Option<u8> {
+    update
+        .split_whitespace()
+        .find_map(|field| field.strip_suffix('%')?.parse().ok())
+}
+
+/// Like [`forward_and_capture`], but instead of forwarding rsync's progress it reports every
+/// change of percentage as a `{"phase":...,"percent":N}` line on stderr.
+fn capture_progress_json(mut reader: impl Read, phase: &str) -> std::io::Result<String> {
+    let mut captured = Vec::new();
+    let mut pending = Vec::new();
+    let mut last_percent = None;
+    let mut buf = [0; 8192];
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        captured.extend_from_slice(&buf[..read]);
+        // rsync rewrites its progress line with carriage returns.
+        for &byte in &buf[..read] {
+            if byte != b'\r' && byte != b'\n' {
+                pending.push(byte);
+                continue;
+            }
+            if let Some(percent) = parse_progress_percent(&String::from_utf8_lossy(&pending)) {
+                if last_percent != Some(percent) {
+                    eprintln!("{{\"phase\":\"{}\",\"percent\":{}}}", phase, percent);
+                    last_percent = Some(percent);
+                }
+            }
+            pending.clear();
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&captured).into_owned())
+}
+
+/// Removes ANSI escape sequences from `text`: CSI sequences like colors (`ESC [ 1;31 m`),
+/// OSC sequences like hyperlinks (`ESC ] ... BEL`) and two-character escapes.
+fn strip_ansi_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Parameters and intermediates up to a final byte in @..~.
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Terminated by BEL or ST (`ESC \`).
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Writes an asciicast v2 file: a JSON header line, then one `[time, "o", output]` line
+/// per output event, times in seconds since the recording started.
+fn write_asciicast(
+    path: &Path,
+    title: &str,
+    started_at: std::time::SystemTime,
+    events: &[(f64, String)],
+) -> std::io::Result<()> {
+    let size = |var: &str, default: u16| {
+        std::env::var(var).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+    };
+    let header = serde_json::json!({
+        "version": 2,
+        "width": size("COLUMNS", 80),
+        "height": size("LINES", 24),
+        "timestamp": started_at
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0),
+        "title": title,
+    });
+
+    let mut cast = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(cast, "{}", header)?;
+    for (time, output) in events {
+        writeln!(cast, "{}", serde_json::json!([time, "o", output]))?;
+    }
+    cast.flush()
+}
+
+/// ssh exits with 255 when it can't connect or loses the connection, rsync and transports
+/// going through ssh pass that on.
+fn connection_failed(status: ExitStatus) -> bool {
+    status.code() == Some(255)
+}
+
+/// Runs `command`, forwarding its stdout to ours as it arrives while also keeping a copy
+/// so it can be inspected afterwards.
+fn output_passthrough(command: &mut Command) -> std::io::Result<(ExitStatus, String)> {
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let captured = forward_and_capture(child.stdout.take().expect("stdout is piped"))?;
+    Ok((child.wait()?, captured))
+}
+
+/// Copies `path`, relative to `remote_dir`, back into `local_root` by packing it into a
+/// compressed tarball on the build server and unpacking the stream locally, a single
+/// transfer instead of one per file. Returns [`None`] if it took longer than `timeout`.
+fn copy_back_tarball(
+    ssh_options: &[String],
+    build_server: &str,
+    remote_dir: &str,
+    path: &str,
+    local_root: &Path,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<bool>> {
+    let path = if path.is_empty() { "." } else { path };
+    let mut pack = ssh_command(ssh_options, build_server)
+        .arg(format!("cd {} && tar -czf - {}", shell_escape_path(remote_dir), shell_escape(path)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut unpack = Command::new("tar")
+        .arg("-xzf")
+        .arg("-")
+        .arg("-C")
+        .arg(local_root)
+        .stdin(pack.stdout.take().expect("stdout is piped"))
+        .spawn()?;
+
+    let packed = match timeout {
+        Some(timeout) => wait_with_timeout(&mut pack, timeout)?,
+        None => pack.wait().map(Some)?,
+    };
+    let packed = match packed {
+        Some(status) => status,
+        None => {
+            unpack.kill()?;
+            unpack.wait()?;
+            return Ok(None);
+        }
+    };
+    let unpacked = unpack.wait()?;
+    Ok(Some(packed.success() && unpacked.success()))
+}
+
+/// Runs `command` with its stdout and stderr piped, calling `on_line` with every line of
+/// either stream as it arrives, along with whether it came from stderr.
+fn run_piped(command: &mut Command, mut on_line: impl FnMut(&str, bool)) -> std::io::Result<ExitStatus> {
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let (sender, receiver) = mpsc::channel();
+
+    let forward = |stream: Box<dyn Read + Send>, is_stderr: bool| {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            while let Ok(read) = reader.read_until(b'\n', &mut line) {
+                if read == 0 {
+                    break;
+                }
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end_matches(['\n', '\r']).to_owned();
+                if sender.send((text, is_stderr)).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        })
+    };
+    let stdout = forward(Box::new(child.stdout.take().expect("stdout is piped")), false);
+    let stderr = forward(Box::new(child.stderr.take().expect("stderr is piped")), true);
+    drop(sender);
+
+    for (line, is_stderr) in receiver {
+        on_line(&line, is_stderr);
+    }
+    let _ = stdout.join();
+    let _ = stderr.join();
+    child.wait()
+}
+
+/// Starts a thread warning every `threshold` without new output, `last_output` being when
+/// the last line arrived. It stops once the returned sender is dropped.
+fn watch_for_stalls(last_output: Arc<Mutex<Instant>>, threshold: Duration) -> (mpsc::Sender<()>, thread::JoinHandle<()>) {
+    let threshold = threshold.as_secs().max(1);
+    let (stop, stopped) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        let mut warned_for = None;
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_secs(1)) {
+            let last = *last_output.lock().unwrap();
+            let silent = last.elapsed().as_secs() / threshold;
+            if silent > 0 && warned_for != Some((last, silent)) {
+                warn!("No output for {}s, build may be stalled", silent * threshold);
+                warned_for = Some((last, silent));
+            }
+        }
+    });
+    (stop, watchdog)
+}
+
+/// Starts a thread printing how long the build has been running every `interval`, whatever
+/// the build itself prints. It stops once the returned sender is dropped.
+fn start_heartbeat(interval: Duration) -> (mpsc::Sender<()>, thread::JoinHandle<()>) {
+    let interval = interval.max(Duration::from_secs(1));
+    let (stop, stopped) = mpsc::channel::<()>();
+    let start = Instant::now();
+    let heartbeat = thread::spawn(move || {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            println!("still building... ({}s elapsed)", start.elapsed().as_secs());
+        }
+    });
+    (stop, heartbeat)
+}
+
+/// Deals `items` out to `n` groups in turn, dropping the groups left empty.
+fn partition_round_robin<T>(items: Vec<T>, n: usize) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = (0..n.max(1)).map(|_| Vec::new()).collect();
+    for (i, item) in items.into_iter().enumerate() {
+        groups[i % n.max(1)].push(item);
+    }
+    groups.retain(|group| !group.is_empty());
+    groups
+}
+
+/// Extracts a numeric field such as `Total bytes sent: 1,234` from rsync's `--stats` output,
+/// adding the values up when the output holds the stats of several transfers.
+fn rsync_stat(output: &str, field: &str) -> Option<u64> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(field)?.strip_prefix(':'))
+        .filter_map(|value| value.split_whitespace().next()?.replace(',', "").parse::<u64>().ok())
+        .fold(None, |total, value| Some(total.unwrap_or(0) + value))
+}
+
+/// Formats a byte count with a decimal unit, e.g. `2.3 MB`.
+fn human_bytes(bytes: u64) -> String {
+    let units = ["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Validates a duration in ssh's time format: one or more numbers, each optionally
+/// followed by a unit (`s`, `m`, `h`, `d` or `w`), e.g. `90`, `30s` or `1h30m`.
+fn parse_ssh_duration(value: &str) -> Result<String, String> {
+    let invalid = || format!("invalid duration '{}' (expected e.g. 30s or 10m)", value);
+    if value.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut digits = 0;
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits += 1;
+        } else if "smhdwSMHDW".contains(c) && digits > 0 {
+            digits = 0;
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(value.to_owned())
+}
+
+/// Validates a `KEY=VAL` environment assignment.
+fn parse_env_assignment(value: &str) -> Result<String, String> {
+    match value.split_once('=') {
+        Some((key, _)) if !key.is_empty() && !key.contains(char::is_whitespace) => Ok(value.to_owned()),
+        _ => Err(format!("invalid environment assignment '{}' (expected KEY=VAL)", value)),
+    }
+}
+
+fn parse_env_name(value: &str) -> Result<String, String> {
+    let valid = value.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(value.to_owned())
+    } else {
+        Err(format!("invalid environment variable name '{}'", value))
+    }
+}
+
+fn parse_transport_command(template: &str) -> Result<String, String> {
+    if !template.contains("{source}") {
+        return Err("the transport command must contain {source}".to_owned());
+    }
+    if !template.contains("{dest}") && !template.contains("{build_path}") {
+        return Err("the transport command must contain {dest} or {build_path}".to_owned());
+    }
+    Ok(template.to_owned())
+}
+
+/// Fills in the `--transport-command` placeholders, shell-escaped for the local shell.
+fn expand_transport_command(template: &str, source: &str, server: &str, build_path: &str) -> String {
+    template
+        .replace("{source}", &shell_escape(source))
+        .replace("{dest}", &shell_escape(&format!("{}:{}", rsync_destination(server), build_path)))
+        .replace("{server}", &shell_escape(server))
+        .replace("{build_path}", &shell_escape(build_path))
+}
+
+/// Quotes `value` for the remote shell.
+fn shell_escape(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Escapes `pattern` for the remote shell, leaving its glob characters (`*`, `?`, `[...]`) active.
+fn shell_escape_glob(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "*?[]-_./+,:=@%^".contains(c) {
+                c.to_string()
+            } else {
+                format!("\\{}", c)
+            }
+        })
+        .collect()
+}
+
+/// The shell command listing the files in `dir` that match the glob `patterns`, one
+/// relative path per line.
+fn artifact_listing_command(dir: &str, patterns: &[String]) -> String {
+    // Unmatched patterns are left as they are by the shell, `-e` weeds them out.
+    let patterns: Vec<_> = patterns.iter().map(|pattern| shell_escape_glob(pattern)).collect();
+    format!(
+        "cd {} && for f in {}; do [ -e \"$f\" ] && printf '%s\\n' \"$f\"; done; true",
+        dir,
+        patterns.join(" ")
+    )
+}
+
+/// Quotes a remote path for the shell, leaving a leading `~/` unquoted so it still
+/// expands to the home directory.
+fn shell_escape_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_escape(rest)),
+        None => shell_escape(path),
+    }
+}
+
+/// Replaces the `{build_path}`, `{workspace}` and `{target}` placeholders of `build_env`
+/// with their shell-escaped values. `{target}` is left alone when there's no target.
+fn expand_build_env(build_env: &str, build_path: &str, workspace: &str, target: Option<&str>) -> String {
+    let mut expanded = build_env
+        .replace("{build_path}", &shell_escape_path(build_path))
+        .replace("{workspace}", &shell_escape_path(workspace));
+    if let Some(target) = target {
+        expanded = expanded.replace("{target}", &shell_escape(target));
+    }
+    expanded
+}
+
+/// Name suffixes of the variables whose values are redacted from the logs even without `--redact`.
+const SENSITIVE_SUFFIXES: &[&str] = &["_TOKEN", "_SECRET", "_PASSWORD"];
+
+/// Whether the value of the variable `key` must be kept out of the logs: it's one of
+/// `redacted` or its name looks like a secret's, e.g. `GITHUB_TOKEN`.
+fn is_sensitive(key: &str, redacted: &[String]) -> bool {
+    let key = key.to_ascii_uppercase();
+    redacted.iter().any(|name| name.eq_ignore_ascii_case(&key))
+        || SENSITIVE_SUFFIXES
+            .iter()
+            .any(|suffix| key.ends_with(suffix) || key == suffix[1..])
+}
+
+/// Splits `build_env` into words the way the remote shell does, so a quoted value
+/// containing spaces stays in one word. The words are returned as written, quotes included.
+fn shell_words(build_env: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in build_env.char_indices() {
+        if start.is_none() {
+            if c.is_whitespace() {
+                continue;
+            }
+            start = Some(i);
+        }
+        if escaped {
+            escaped = false;
+        } else if quote == Some(c) {
+            quote = None;
+        } else if quote == Some('\'') {
+            // Nothing is special inside single quotes.
+        } else if c == '\\' {
+            escaped = true;
+        } else if quote.is_none() && (c == '"' || c == '\'') {
+            quote = Some(c);
+        } else if quote.is_none() && c.is_whitespace() {
+            words.extend(start.take().map(|start| &build_env[start..i]));
+        }
+    }
+    words.extend(start.map(|start| &build_env[start..]));
+    words
+}
+
+/// `build_env` with the values of the sensitive variables (see [`is_sensitive`]) replaced
+/// by `***`, for logging.
+fn redact_env(build_env: &str, redacted: &[String]) -> String {
+    shell_words(build_env)
+        .into_iter()
+        .map(|assignment| match assignment.split_once('=') {
+            Some((key, _)) if is_sensitive(key, redacted) => format!("{}=***", key),
+            _ => assignment.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits the `keyring:<service>/<account>` assignments out of `build_env` and resolves
+/// their values from the system keyring. Returns the remaining assignments and the
+/// resolved secrets, or an error naming the entry that couldn't be read.
+fn resolve_keyring_env(build_env: &str) -> Result<(String, Vec<(String, String)>), String> {
+    if !build_env.contains("=keyring:") {
+        return Ok((build_env.to_owned(), Vec::new()));
+    }
+
+    let mut public = Vec::new();
+    let mut secrets = Vec::new();
+
+    for assignment in build_env.split_whitespace() {
+        let reference = assignment
+            .split_once('=')
+            .and_then(|(key, value)| Some((key, value.strip_prefix("keyring:")?)));
+        let (key, reference) = match reference {
+            Some(reference) => reference,
+            None => {
+                public.push(assignment);
+                continue;
+            }
+        };
+
+        let (service, account) = reference
+            .split_once('/')
+            .ok_or_else(|| format!("{}: expected keyring:<service>/<account>", key))?;
+        let secret = keyring::Entry::new(service, account)
+            .and_then(|entry| entry.get_password())
+            .map_err(|e| format!("{}: can't read keyring entry {}/{} ({})", key, service, account, e))?;
+        secrets.push((key.to_owned(), secret));
+    }
+
+    Ok((public.join(" "), secrets))
+}
+
+/// Marker file touched in the build directory by every upload, `--auto-gc` removes the
+/// directories whose marker is the oldest. The directory's own mtime is the local one's.
+const LAST_USED_MARKER: &str = ".remocom-last-used";
+
+/// The `--rsync-path` of the uploads, creating `build_path` and marking it as used.
+fn remote_rsync_path(build_path: &str) -> String {
+    format!(
+        "mkdir -p {dir} && touch {dir}{marker} && rsync",
+        dir = shell_escape_path(build_path),
+        marker = LAST_USED_MARKER
+    )
+}
+
+/// The rsync command uploading the project to `build_path`, without its source and
+/// destination.
+fn upload_rsync(
+    rsync_shell: &str,
+    build_path: &str,
+    toolchain_filename: Option<&str>,
+    hidden: bool,
+    normalize_times: bool,
+    inplace: bool,
+    max_file_size: Option<u64>,
+) -> Command {
+    let mut rsync_to = Command::new("rsync");
+
+    // `-a` without `-t`: transferred files get fresh mtimes, so cargo's fingerprints
+    // follow the sync order. Remote mtimes then never match the local ones, so files
+    // are compared by checksum, otherwise every file would be resent and rebuilt.
+    if normalize_times {
+        rsync_to.arg("-rlpgoD").arg("--checksum");
+    } else {
+        rsync_to.arg("-a");
+    }
+
+    rsync_to
+        .arg("-e")
+        .arg(rsync_shell)
+        .arg("--delete")
+        .arg("--exclude")
+        .arg("--target")
+        // remocom's own state on the build server must survive `--delete`.
+        .arg("--exclude")
+        .arg("/.remocom-*");
+
+    if !hidden {
+        // A hidden toolchain file is still needed remotely.
+        if let Some(name) = toolchain_filename.filter(|name| name.starts_with('.')) {
+            rsync_to.arg("--include").arg(name);
+        }
+        rsync_to.arg("--exclude").arg(".*");
+    }
+
+    // Writing in place avoids needing room for a second copy of large files, at the cost
+    // of atomicity: an interrupted transfer leaves the remote file partially updated.
+    if inplace {
+        rsync_to.arg("--inplace");
+    }
+
+    // `--info=skip` reports every file left out as `<name> is over max-size`.
+    if let Some(max_size) = max_file_size {
+        rsync_to.arg(format!("--max-size={}", max_size)).arg("--info=skip");
+    }
+
+    rsync_to
+        .arg("--rsync-path")
+        .arg(remote_rsync_path(build_path))
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit());
+    rsync_to
+}
+
+/// The files rsync's `--info=skip` reports as left out by `--max-size`.
+fn oversized_files(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        // Progress updates may precede the message on its line, separated by carriage returns.
+        .filter_map(|line| line.rsplit('\r').next()?.trim().strip_suffix(" is over max-size"))
+        .collect()
+}
+
+/// Splits `remote` into its optional `user@` part and its host.
+fn split_remote(remote: &str) -> (&str, &str) {
+    match remote.rfind('@') {
+        Some(at) => remote.split_at(at + 1),
+        None => ("", remote),
+    }
+}
+
+/// `remote` as ssh expects it: IPv6 addresses without brackets, zone included
+/// (`user@fe80::1%eth0`).
+fn ssh_destination(remote: &str) -> String {
+    let (user, host) = split_remote(remote);
+    match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(address) => format!("{}{}", user, address),
+        None => remote.to_owned(),
+    }
+}
+
+/// `remote` as the host of an rsync `host:path`: IPv6 addresses are bracketed so their
+/// colons aren't taken for the path separator (`user@[fe80::1%eth0]`).
+fn rsync_destination(remote: &str) -> String {
+    let (user, host) = split_remote(remote);
+    if host.contains(':') && !host.starts_with('[') {
+        format!("{}[{}]", user, host)
+    } else {
+        remote.to_owned()
+    }
+}
+
+/// The ssh options trusting only the hosts in `known_hosts`. New hosts are added to it on
+/// first use, unless `strict`.
+fn known_hosts_options(known_hosts: &Path, strict: bool) -> Vec<String> {
+    // ssh splits the value into several files on whitespace, unless quoted.
+    let quoted = known_hosts.to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+    vec![
+        "-o".to_owned(),
+        format!("UserKnownHostsFile=\"{}\"", quoted),
+        "-o".to_owned(),
+        format!("StrictHostKeyChecking={}", if strict { "yes" } else { "accept-new" }),
+    ]
+}
+
+/// Creates an ssh command to `build_server` with the shared connection options applied.
+fn ssh_command(ssh_options: &[String], build_server: &str) -> Command {
+    let mut ssh = Command::new("ssh");
+    ssh.args(ssh_options).arg(ssh_destination(build_server));
+    ssh
+}
+
+/// Writes a shell script that runs ssh with `ssh_options` and opens a login shell in
+/// `remote_dir` on the build server.
+fn write_connect_script(
+    path: &Path,
+    ssh_options: &[String],
+    build_server: &str,
+    remote_dir: &str,
+) -> std::io::Result<()> {
+    let remote_command = format!("cd {} && exec \"$SHELL\" -l", shell_escape_path(remote_dir));
+    let mut script = String::from("#!/bin/sh\nexec ssh -t");
+    for option in ssh_options {
+        script.push(' ');
+        script.push_str(&shell_escape(option));
+    }
+    script.push_str(&format!(
+        " {} {}\n",
+        shell_escape(&ssh_destination(build_server)),
+        shell_escape(&remote_command)
+    ));
+    std::fs::write(path, script)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// The `-e` value making rsync connect with the same options as [`ssh_command`].
+/// rsync splits it on spaces but honors quotes, a doubled quote standing for itself within
+/// quotes, so arguments containing spaces or quotes are single-quoted.
+fn rsync_shell(ssh_options: &[String]) -> String {
+    let mut shell = String::from("ssh");
+    for option in ssh_options {
+        shell.push(' ');
+        if option.contains([' ', '\'', '"']) {
+            shell.push_str(&format!("'{}'", option.replace('\'', "''")));
+        } else {
+            shell.push_str(option);
+        }
+    }
+    shell
+}
+
+/// Extracts the `host:` triple from `rustc -vV` output.
+fn parse_rustc_host(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("host:"))
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty())
+}
+
+/// Best guess of the target triple for a Linux machine from its `uname -m`.
+fn triple_from_uname(machine: &str) -> Option<String> {
+    let triple = match machine.trim() {
+        "x86_64" | "amd64" => "x86_64-unknown-linux-gnu",
+        "aarch64" | "arm64" => "aarch64-unknown-linux-gnu",
+        "armv7l" => "armv7-unknown-linux-gnueabihf",
+        "i686" | "i386" => "i686-unknown-linux-gnu",
+        "riscv64" => "riscv64gc-unknown-linux-gnu",
+        "ppc64le" => "powerpc64le-unknown-linux-gnu",
+        "s390x" => "s390x-unknown-linux-gnu",
+        _ => return None,
+    };
+    Some(triple.to_owned())
+}
+
+/// Determines the build server's host triple, asking its rustc and falling back to
+/// guessing from `uname -m` when rustc isn't available.
+fn detect_remote_triple(ssh_options: &[String], build_server: &str, env: &str) -> Option<String> {
+    let remote_output = |command: String| {
+        ssh_command(ssh_options, build_server)
+            .arg(command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let rustc_host = remote_output(format!("source {}; rustc -vV", env))
+        .and_then(|output| parse_rustc_host(&output));
+    if rustc_host.is_some() {
+        return rustc_host;
+    }
+
+    warn!("Can't run rustc on the build server, guessing the host triple from uname");
+    remote_output("uname -m".to_owned()).and_then(|machine| triple_from_uname(&machine))
+}
+
+/// Whether an ssh connection to `build_server` is established within 5 seconds. In batch
+/// mode ssh never prompts, so servers that need a password count as unreachable.
+fn server_reachable(ssh_options: &[String], build_server: &str, batch_mode: bool) -> bool {
+    let mut ssh = ssh_command(ssh_options, build_server);
+    if batch_mode {
+        ssh.arg("-o").arg("BatchMode=yes");
+    }
+    ssh.arg("-o")
+        .arg("ConnectTimeout=5")
+        .arg("true")
+        .stdin(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Clock skew tolerated by `--check-clock`, `date +%s` having a one second resolution.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+/// How far the build server's clock is ahead of the local one in seconds (negative when
+/// behind), compared to the local time halfway through the ssh round trip.
+fn remote_clock_skew(ssh_options: &[String], build_server: &str) -> Option<f64> {
+    let unix_now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_secs_f64())
+            .unwrap_or(0.0)
+    };
+    let sent = unix_now();
+    let output = ssh_command(ssh_options, build_server)
+        .arg("date +%s")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    let received = unix_now();
+    let remote: f64 = String::from_utf8_lossy(&output.stdout).trim().parse().ok()?;
+    Some(remote - (sent + received) / 2.0)
+}
+
+/// Checks whether `program` can be found on the build server's `PATH`.
+fn remote_has_command(ssh_options: &[String], build_server: &str, program: &str) -> bool {
+    ssh_command(ssh_options, build_server)
+        .arg(format!("command -v {} >/dev/null", shell_escape(program)))
+        .stdin(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Takes the build directory lock, a directory created atomically with `mkdir`. Locks
+/// older than `stale_after` seconds are removed. Gives up after `timeout`.
+fn acquire_build_lock(
+    ssh_options: &[String],
+    build_server: &str,
+    build_path: &str,
+    lock_path: &str,
+    timeout: Duration,
+    stale_after: Option<u64>,
+) -> bool {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let acquired = ssh_command(ssh_options, build_server)
+            .arg(format!(
+                "mkdir -p {} && mkdir {} 2>/dev/null",
+                shell_escape_path(build_path),
+                shell_escape_path(lock_path)
+            ))
+            .stdin(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        if acquired {
+            return true;
+        }
+
+        if let Some(stale_after) = stale_after {
+            let age = ssh_command(ssh_options, build_server)
+                .arg(format!(
+                    "echo $(( $(date +%s) - $(stat -c %Y {}) ))",
+                    shell_escape_path(lock_path)
+                ))
+                .stdin(Stdio::null())
+                .output()
+                .ok()
+                .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse::<u64>().ok());
+            if let Some(age) = age.filter(|&age| age > stale_after) {
+                warn!("Removing stale build directory lock, it's {}s old", age);
+                release_build_lock(ssh_options, build_server, lock_path);
+                continue;
+            }
+        }
+
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        if !waiting {
+            info!("Build directory is locked by another run, waiting");
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn release_build_lock(ssh_options: &[String], build_server: &str, lock_path: &str) {
+    let released = ssh_command(ssh_options, build_server)
+        .arg(format!("rm -rf {}", shell_escape_path(lock_path)))
+        .stdin(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if !released {
+        warn!("Failed to remove the build directory lock {}", lock_path);
+    }
+}
+
+fn parse_remote_build_dir(value: &str) -> Result<String, String> {
+    if !value.starts_with('/') && !value.starts_with("~/") {
+        return Err(format!("build directory '{}' must be absolute or start with ~/", value));
+    }
+    if value.contains(char::is_whitespace) {
+        return Err(format!("build directory '{}' can't contain whitespace", value));
+    }
+    // The upload deletes whatever else is in the directory.
+    let build_dir = value.trim_end_matches('/');
+    if build_dir.is_empty() || build_dir == "~" {
+        return Err("the build directory can't be the root or home directory".to_owned());
+    }
+    Ok(format!("{}/", build_dir))
+}
+
+/// Validates a `--workdir`: a relative path that can't lead out of the build directory.
+fn parse_workdir(value: &str) -> Result<String, String> {
+    use std::path::Component;
+
+    let path = Path::new(value);
+    if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("working directory '{}' must be relative to the uploaded root, without '..'", value));
+    }
+    Ok(value.trim_end_matches('/').to_owned())
+}
+
+/// Where the build command `cd`s to: the `--workdir` within `build_path`, shell-escaped,
+/// or else the project.
+fn remote_workdir(build_path: &str, workdir: Option<&str>, project_path: &str) -> String {
+    match workdir {
+        Some(workdir) => shell_escape_path(&format!("{}{}", build_path, workdir)),
+        None => project_path.to_owned(),
+    }
+}
+
+fn parse_deploy_target(value: &str) -> Result<String, String> {
+    match value.split_once(':') {
+        Some((host, path)) if !host.is_empty() && !path.is_empty() && !host.contains('/') => Ok(value.to_owned()),
+        _ => Err(format!("invalid deploy destination '{}' (expected user@host:path)", value)),
+    }
+}
+
+/// The binary a `--deploy` sends: the one selected with `--bin`, or the package's only one.
+fn deployed_binary(metadata: &cargo_metadata::Metadata, options: &[String]) -> Result<String, String> {
+    if let Some(name) = cargo_option_value(options, "--bin") {
+        return Ok(name);
+    }
+    let package = metadata
+        .root_package()
+        .or_else(|| metadata.packages.first())
+        .ok_or("the workspace has no package")?;
+    let bins: Vec<_> = package
+        .targets
+        .iter()
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .map(|target| target.name.clone())
+        .collect();
+    match bins.len() {
+        1 => Ok(bins[0].clone()),
+        0 => Err(format!("package '{}' has no binary", package.name)),
+        _ => Err(format!("package '{}' has several binaries, select one with --bin", package.name)),
+    }
+}
+
+/// Parses `sha256sum` output into a map of file to hash.
+fn parse_checksums(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (hash, file) = line.split_once(char::is_whitespace)?;
+            // `sha256sum` marks binary mode with a `*` before the name.
+            let file = file.trim_start().trim_start_matches('*');
+            Some((file.to_owned(), hash.to_owned()))
+        })
+        .collect()
+}
+
+/// The files whose hash differs between two `sha256sum` outputs, or that only one of them has.
+fn changed_checksums(old: &str, new: &str) -> Vec<String> {
+    let old = parse_checksums(old);
+    let new = parse_checksums(new);
+    let files: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    files
+        .into_iter()
+        .filter(|file| old.get(*file) != new.get(*file))
+        .cloned()
+        .collect()
+}
+
+/// `--include` patterns for the source files of every target of the workspace's packages,
+/// anchored at `sync_root`. cargo can't load a package whose targets are missing.
+fn target_source_patterns(metadata: &cargo_metadata::Metadata, sync_root: &Path) -> Vec<String> {
+    let canonical_root = sync_root.canonicalize().ok();
+    metadata
+        .packages
+        .iter()
+        .flat_map(|package| &package.targets)
+        .filter_map(|target| {
+            let relative = target.src_path.strip_prefix(sync_root).ok().or_else(|| {
+                target.src_path.strip_prefix(canonical_root.as_ref()?).ok()
+            })?;
+            // rsync only treats the backslashes as escapes in patterns with wildcards.
+            let escaped: String = relative
+                .to_string_lossy()
+                .chars()
+                .flat_map(|c| if "*?[".contains(c) { vec!['\\', c] } else { vec![c] })
+                .collect();
+            Some(format!("/{}", escaped))
+        })
+        .collect()
+}
+
+/// The value of a cargo option given as `--name value` or `--name=value`.
+fn cargo_option_value(options: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    options.iter().enumerate().find_map(|(i, option)| {
+        if option == name {
+            options.get(i + 1).cloned()
+        } else {
+            option.strip_prefix(&prefix).map(str::to_owned)
+        }
+    })
+}
+
+/// The target cargo builds `variant` for: the `--target` of the cargo options, otherwise the
+/// matrix target or the detected host triple.
+fn build_target(options: &[String], variant: &BuildVariant) -> Option<String> {
+    cargo_option_value(options, "--target").or_else(|| variant.target.clone())
+}
+
+/// The `--copy-back` path `file_name` relative to the remote `target/`. With an explicit
+/// target cargo nests the artifacts under the triple, so the paths are relative to that.
+fn copy_back_path(options: &[String], variant: &BuildVariant, file_name: &str) -> String {
+    match build_target(options, variant) {
+        Some(triple) => format!("{}/{}", triple, file_name),
+        None => file_name.to_owned(),
+    }
+}
+
+/// The directory of `target` cargo puts the artifacts in for the profile selected by `options`.
+fn profile_dir(options: &[String]) -> String {
+    match cargo_option_value(options, "--profile") {
+        Some(profile) if profile == "dev" || profile == "test" => "debug".to_owned(),
+        Some(profile) if profile == "bench" => "release".to_owned(),
+        Some(profile) => profile,
+        None if options.iter().any(|option| option == "--release" || option == "-r") => "release".to_owned(),
+        None => "debug".to_owned(),
+    }
+}
+
+fn parse_jobs_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(format!("invalid jobs fraction '{}' (expected a number in (0.0, 1.0])", value)),
+    }
+}
+
+/// Number of CPUs of the build server, from `nproc`.
+fn remote_cpu_count(ssh_options: &[String], build_server: &str) -> Option<u32> {
+    let output = ssh_command(ssh_options, build_server)
+        .arg("nproc")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Free space in KiB of the file system holding `~/remote-builds` on the build server.
+fn remote_free_kib(ssh_options: &[String], build_server: &str) -> Option<u64> {
+    let output = ssh_command(ssh_options, build_server)
+        .arg("mkdir -p ~/remote-builds && df -Pk ~/remote-builds | tail -n 1")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    // Filesystem 1024-blocks Used Available Capacity Mounted on
+    String::from_utf8_lossy(&output.stdout).split_whitespace().nth(3)?.parse().ok()
+}
+
+/// The build directories of a `<unix time> <dir>` listing, least recently used first,
+/// except `keep`.
+fn gc_candidates<'a>(listing: &'a str, keep: &str) -> Vec<&'a str> {
+    let mut dirs: Vec<(u64, &str)> = listing
+        .lines()
+        .filter_map(|line| {
+            let (time, dir) = line.split_once(' ')?;
+            Some((time.parse().ok()?, dir))
+        })
+        .filter(|(_, dir)| Path::new(dir).file_name().is_none_or(|name| name != keep))
+        .collect();
+    dirs.sort();
+    dirs.into_iter().map(|(_, dir)| dir).collect()
+}
+
+/// Deletes the least recently used build directories, except `keep` and those locked by
+/// a running build, until at least `min_free_kib` are free.
+fn collect_build_dirs(ssh_options: &[String], build_server: &str, keep: &str, min_free_kib: u64) {
+    let mut free = match remote_free_kib(ssh_options, build_server) {
+        Some(free) if free < min_free_kib => free,
+        Some(_) => return,
+        None => {
+            warn!("Can't get the build server's free disk space, skipping --auto-gc");
+            return;
+        }
+    };
+    info!("Only {} MiB free on the build server, removing old build directories", free / 1024);
+
+    // Directories uploaded before the marker existed fall back to their own mtime.
+    let listing = ssh_command(ssh_options, build_server)
+        .arg(format!(
+            "for dir in ~/remote-builds/*/; do [ -e \"$dir.remocom-lock\" ] || \
+             printf '%s %s\\n' \"$(stat -c %Y \"$dir{marker}\" 2>/dev/null || stat -c %Y \"$dir\")\" \"$dir\"; done",
+            marker = LAST_USED_MARKER
+        ))
+        .stdin(Stdio::null())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+
+    let start_free = free;
+    for dir in gc_candidates(&listing, keep) {
+        if free >= min_free_kib {
+            break;
+        }
+        let removed = ssh_command(ssh_options, build_server)
+            .arg(format!("rm -rf {}", shell_escape(dir)))
+            .stdin(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        if !removed {
+            warn!("Failed to remove {}", dir);
+            continue;
+        }
+        free = remote_free_kib(ssh_options, build_server).unwrap_or(free);
+        info!("Removed {}", dir);
+    }
+    info!("Reclaimed {} MiB on the build server", free.saturating_sub(start_free) / 1024);
+    if free < min_free_kib {
+        warn!("Still only {} MiB free on the build server", free / 1024);
+    }
+}
+
+/// Names rustup reads a toolchain file from, in its order of precedence.
+const TOOLCHAIN_FILE_NAMES: &[&str] = &["rust-toolchain", "rust-toolchain.toml"];
+
+/// Finds the toolchain file rustup would use in `project_dir`: the closest `name` (by default
+/// any of [`TOOLCHAIN_FILE_NAMES`]) in it or its parents up to `sync_root`, which is all that's
+/// uploaded. Returns its name.
+fn detect_toolchain_file(project_dir: &Path, sync_root: &Path, name: Option<&str>) -> Option<String> {
+    let names = match name {
+        Some(name) => vec![name],
+        None => TOOLCHAIN_FILE_NAMES.to_vec(),
+    };
+    for dir in project_dir.ancestors() {
+        if let Some(found) = names.iter().find(|name| dir.join(name).is_file()) {
+            return Some((*found).to_owned());
+        }
+        if dir == sync_root {
+            break;
+        }
+    }
+    None
+}
+
+/// Preflight checks that recently passed on a build server, persisted in the XDG cache
+/// directory so that back to back runs don't repeat them.
+struct PreflightCache {
+    path: Option<PathBuf>,
+    ttl: Duration,
+    /// Check name to the unix time it last passed.
+    passed: BTreeMap<String, u64>,
+}
+
+impl PreflightCache {
+    /// The cache file name for `build_server`. The slug alone could mix up servers, and
+    /// the ssh options, e.g. a ProxyCommand, may well lead to another machine.
+    fn file_name(build_server: &str, ssh_options: &[String]) -> String {
+        let mut hasher = DefaultHasher::new();
+        build_server.hash(&mut hasher);
+        ssh_options.hash(&mut hasher);
+        format!("preflight-{}-{:016x}", slugify(build_server), hasher.finish())
+    }
+
+    fn load(build_server: &str, ssh_options: &[String], ttl: Duration, enabled: bool) -> Self {
+        let path = if enabled {
+            xdg::BaseDirectories::with_prefix("remocom")
+                .ok()
+                .and_then(|base| base.place_cache_file(Self::file_name(build_server, ssh_options)).ok())
+        } else {
+            None
+        };
+        let passed = path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (time, check) = line.split_once(' ')?;
+                        Some((check.to_owned(), time.parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        PreflightCache { path, ttl, passed }
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Runs `check` unless it passed within the TTL. Only successes are recorded.
+    fn check(&mut self, name: &str, check: impl FnOnce() -> bool) -> bool {
+        let now = Self::now();
+        if self.path.is_some() {
+            if let Some(&time) = self.passed.get(name) {
+                if now.saturating_sub(time) < self.ttl.as_secs() {
+                    info!("Skipping preflight check '{}', it passed {}s ago", name, now - time);
+                    return true;
+                }
+            }
+        }
+
+        let ok = check();
+        if let (true, Some(path)) = (ok, &self.path) {
+            self.passed.insert(name.to_owned(), now);
+            let contents: String = self
+                .passed
+                .iter()
+                .map(|(check, time)| format!("{} {}\n", time, check))
+                .collect();
+            if let Err(e) = std::fs::write(path, contents) {
+                warn!("Failed to update the preflight cache {:?} (error: {})", path, e);
+            }
+        }
+        ok
+    }
+}
+
+/// Builds the `systemd-run` prefix that confines the remote cargo command to a
+/// transient scope with the requested limits.
+fn systemd_run_prefix(memory_max: Option<&str>, cpu_quota: Option<&str>) -> String {
+    let mut prefix = String::from("systemd-run --user --scope");
+    if let Some(memory_max) = memory_max {
+        prefix.push_str(&format!(" -p MemoryMax={}", memory_max));
+    }
+    if let Some(cpu_quota) = cpu_quota {
+        prefix.push_str(&format!(" -p CPUQuota={}", cpu_quota));
+    }
+    prefix
+}
+
+/// The `unset` statements of `--unset-env`, run right before cargo.
+fn unset_statements(keys: &[String]) -> String {
+    keys.iter().map(|key| format!("unset {}; ", key)).collect()
+}
+
+/// The parts of the remote shell command running one build.
+struct BuildCommand<'a> {
+    /// The environment profile sourced first.
+    env: &'a str,
+    /// `rustup default <toolchain>; `, or nothing.
+    rustup_command: &'a str,
+    /// Where cargo runs, already escaped for the shell.
+    workdir: &'a str,
+    /// Shell statements run right before cargo.
+    setup: &'a str,
+    /// `cargo`, or `cross` for `--use-cross`.
+    program: &'a str,
+    command: &'a str,
+    options: &'a [String],
+    message_format_json: bool,
+    target: Option<&'a str>,
+    /// Print cargo's unit graph instead of building, for `--build-graph`.
+    unit_graph: bool,
+    /// The `systemd-run` prefix, if any.
+    prefix: Option<&'a str>,
+    build_env: &'a str,
+    /// The remote file the output is teed to, and whether to append to it.
+    log: Option<(&'a str, bool)>,
+    run_as: Option<&'a str>,
+}
+
+impl BuildCommand<'_> {
+    /// The remote shell command, built again when `--oom-retry` lowers the number of jobs.
+    fn script(&self, jobs: Option<u32>) -> String {
+        // Right after the subcommand, later options may be passed on to the binary (`-- ...`).
+        let mut added_options = jobs.map(|jobs| format!("-j {} ", jobs)).unwrap_or_default();
+        if self.message_format_json && !self.options.iter().any(|option| option.starts_with("--message-format")) {
+            added_options.push_str("--message-format=json ");
+        }
+        if let Some(target) = self.target {
+            added_options.push_str(&format!("--target {} ", target));
+        }
+        if self.unit_graph {
+            added_options.push_str("--unit-graph -Z unstable-options ");
+        }
+        let mut cargo_command = format!(
+            "{} {} {}{}",
+            self.program,
+            self.command,
+            added_options,
+            self.options.join(" ")
+        );
+        if let Some(prefix) = self.prefix {
+            cargo_command = format!("{} {}", prefix, cargo_command);
+        }
+        cargo_command = format!("{} {}", self.build_env, cargo_command);
+        if let Some((log, append)) = self.log {
+            // Later builds of a matrix append, so the log covers the whole run.
+            cargo_command = format!(
+                "set -o pipefail; {} 2>&1 | tee {}{}",
+                cargo_command,
+                if append { "-a " } else { "" },
+                log
+            );
+        }
+
+        if let Some(user) = self.run_as {
+            cargo_command = format!(
+                "sudo -u {} -E bash -lc {}",
+                shell_escape(user),
+                shell_escape(&cargo_command)
+            );
+        }
+
+        format!(
+            "source {}; {}cd {}; {}{}",
+            self.env, self.rustup_command, self.workdir, self.setup, cargo_command
+        )
+    }
+}
+
+/// One remote build of a `--matrix-*` run. `None` fields use the regular settings.
+#[derive(Debug, Clone, Default)]
+struct BuildVariant {
+    toolchain: Option<String>,
+    target: Option<String>,
+}
+
+/// Expands the matrix options into the cartesian product of builds to run. Without
+/// any matrix options this is a single build with the regular settings.
+fn build_matrix(toolchains: &[String], targets: &[String]) -> Vec<BuildVariant> {
+    let toolchains: Vec<Option<String>> = if toolchains.is_empty() {
+        vec![None]
+    } else {
+        toolchains.iter().cloned().map(Some).collect()
+    };
+    let targets: Vec<Option<String>> = if targets.is_empty() {
+        vec![None]
+    } else {
+        targets.iter().cloned().map(Some).collect()
+    };
+
+    toolchains
+        .iter()
+        .flat_map(|toolchain| {
+            targets.iter().map(move |target| BuildVariant {
+                toolchain: toolchain.clone(),
+                target: target.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Prints the outcome of every build of a matrix run.
+fn print_matrix_summary(results: &[(BuildVariant, ExitStatus, Duration)], rustup_default: &str) {
+    println!("{:<20} {:<36} {:<8} {:>10}", "toolchain", "target", "result", "time");
+    for (variant, status, duration) in results {
+        println!(
+            "{:<20} {:<36} {:<8} {:>9.1}s",
+            variant.toolchain.as_deref().unwrap_or(rustup_default),
+            variant.target.as_deref().unwrap_or("-"),
+            if status.success() { "ok" } else { "FAILED" },
+            duration.as_secs_f64()
+        );
+    }
+}
+
+/// Asks a yes/no question on the terminal. Anything but an explicit yes, including a
+/// closed stdin, counts as no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Counts the entries of rsync's `--itemize-changes` output that changed file contents
+/// or the tree layout. Attribute-only updates (e.g. directory mtimes) aren't counted.
+fn count_itemized_changes(output: &str) -> usize {
+    // Progress updates are separated by carriage returns, itemized entries by newlines.
+    output
+        .split(['\n', '\r'])
+        .filter(|line| {
+            let mut chars = line.chars();
+            match (chars.next(), chars.next()) {
+                (Some('*'), _) => line.starts_with("*deleting"),
+                (Some(update), Some(kind)) => "<>ch".contains(update) && "fdLDS".contains(kind),
+                _ => false,
+            }
+        })
+        .count()
+}
+
+/// mDNS service type build servers advertise themselves under.
+const MDNS_SERVICE_TYPE: &str = "_remocom._tcp.local.";
+
+/// Browses mDNS for advertised build servers during `timeout`. The ssh destination of a
+/// server is its `remote` TXT property, or its advertised host name if that's missing.
+fn discover_build_servers(timeout: Duration) -> Vec<String> {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            warn!("Can't start mDNS discovery (error: {})", e);
+            return Vec::new();
+        }
+    };
+    let receiver = match daemon.browse(MDNS_SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            warn!("Can't browse for build servers (error: {})", e);
+            return Vec::new();
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+    let mut servers = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(service)) => {
+                let server = service
+                    .get_property_val_str("remote")
+                    .map(String::from)
+                    .unwrap_or_else(|| service.get_hostname().trim_end_matches('.').to_owned());
+                if !servers.contains(&server) {
+                    servers.push(server);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    let _ = daemon.shutdown();
+    servers
+}
+
+/// How a build server is picked from the configured `pool`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PoolStrategy {
+    /// The first server of the pool.
+    First,
+    /// The reachable server with the lowest one minute load average.
+    LeastLoaded,
+}
+
+impl std::str::FromStr for PoolStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "first" => Ok(PoolStrategy::First),
+            "least-loaded" => Ok(PoolStrategy::LeastLoaded),
+            _ => Err(format!("unknown pool strategy '{}' (expected first or least-loaded)", value)),
+        }
+    }
+}
+
+/// Reads the one minute load average from `uptime` output, e.g.
+/// `10:00  up 3 days, load average: 0.52, 0.58, 0.59` (`load averages: 0.52 0.58 0.59` on macOS).
+fn parse_load_average(uptime: &str) -> Option<f64> {
+    let (_, loads) = uptime.rsplit_once("load average")?;
+    loads
+        .trim_start_matches('s')
+        .trim_start_matches(':')
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .find(|value| !value.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// Probes the load of every server at once and returns the least loaded one. Servers that
+/// don't answer within `timeout` are skipped.
+fn least_loaded_server(ssh_options: &[String], servers: &[String], timeout: Duration) -> Option<(String, f64)> {
+    let probes: Vec<_> = servers
+        .iter()
+        .map(|server| {
+            let mut probe = ssh_command(ssh_options, server);
+            probe
+                .arg("-o")
+                .arg("BatchMode=yes")
+                .arg("-o")
+                .arg(format!("ConnectTimeout={}", timeout.as_secs().max(1)))
+                .arg("uptime")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null());
+            let server = server.clone();
+            thread::spawn(move || {
+                let mut child = probe.spawn().ok()?;
+                let mut stdout = child.stdout.take()?;
+                let status = wait_with_timeout(&mut child, timeout).ok()??;
+                let mut uptime = String::new();
+                stdout.read_to_string(&mut uptime).ok()?;
+                if !status.success() {
+                    return None;
+                }
+                parse_load_average(&uptime).map(|load| (server, load))
+            })
+        })
+        .collect();
+
+    let mut loads = Vec::new();
+    for (server, probe) in servers.iter().zip(probes) {
+        match probe.join().ok().flatten() {
+            Some((server, load)) => {
+                info!("Pool server '{}' load average {:.2}", server, load);
+                loads.push((server, load));
+            }
+            None => warn!("Pool server '{}' didn't report its load, skipping it", server),
+        }
+    }
+    loads.into_iter().min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Settings read from a `remocom-config.toml` file.
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    /// Build server used when `--remote` isn't given.
+    remote: Option<String>,
+
+    /// Short names for build servers, e.g. `fast = "me@bigbox"` allows `--remote fast`.
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+
+    /// Build servers to pick from when no other remote is given, see `--pool-strategy`.
+    #[serde(default)]
+    pool: Vec<String>,
+
+    /// Named lists of `KEY=VALUE` assignments selected with `--env-set`.
+    #[serde(default)]
+    env_sets: HashMap<String, Vec<String>>,
+
+    /// cargo command, with options, run when none is given, e.g. `test --workspace`.
+    default_command: Option<String>,
+
+    /// Glob patterns, relative to the workspace, of the artifacts a bare `--copy-back`
+    /// transfers instead of the whole target directory.
+    #[serde(default)]
+    copy_back: Vec<String>,
+
+    /// Build servers the run moves on to, in order, when ssh can't connect to the remote
+    /// during the upload or the build.
+    #[serde(default)]
+    fallbacks: Vec<String>,
+
+    /// Named server setups selected with `--config-profile`.
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+
+    /// Keys remocom doesn't know, most likely typos. Only reported by `check-config`.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// A `[profiles.<name>]` section of the config.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Profile {
+    /// Another profile of the same config this one builds upon.
+    extends: Option<String>,
+
+    /// Build server used when `--remote` isn't given.
+    remote: Option<String>,
+
+    /// `KEY=VALUE` assignments added to the build environment, `--build-env` winning.
+    #[serde(default)]
+    build_env: Vec<String>,
+
+    /// Variables unset before running cargo, like `--unset-env`.
+    #[serde(default)]
+    unset_env: Vec<String>,
+
+    /// Keys remocom doesn't know, most likely typos. Only reported by `check-config`.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Profile {
+    /// Fills what this profile leaves unset from `parent`. Lists are combined, the variables
+    /// this profile assigns replacing the parent's assignments.
+    fn inherit(self, parent: Profile) -> Profile {
+        let key = |assignment: &String| assignment.split_once('=').map(|(key, _)| key.to_owned());
+        let assigned: BTreeSet<_> = self.build_env.iter().filter_map(key).collect();
+        Profile {
+            extends: parent.extends,
+            remote: self.remote.or(parent.remote),
+            build_env: parent
+                .build_env
+                .into_iter()
+                .filter(|assignment| key(assignment).is_none_or(|key| !assigned.contains(&key)))
+                .chain(self.build_env)
+                .collect(),
+            unset_env: parent.unset_env.into_iter().chain(self.unset_env).collect(),
+            unknown: self.unknown,
+        }
+    }
+}
+
+/// Looks up the profile `name` and merges in the profiles it extends, failing on unknown
+/// or circular references.
+fn resolve_profile(profiles: &HashMap<String, Profile>, name: &str) -> Result<Profile, String> {
+    let mut chain = vec![name.to_owned()];
+    let mut profile = profiles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("no profile '{}' in the config", name))?;
+    while let Some(parent_name) = profile.extends.clone() {
+        if chain.contains(&parent_name) {
+            chain.push(parent_name);
+            return Err(format!("circular profile inheritance: {}", chain.join(" -> ")));
+        }
+        let parent = profiles
+            .get(&parent_name)
+            .cloned()
+            .ok_or_else(|| format!("profile '{}' extends unknown profile '{}'", chain[chain.len() - 1], parent_name))?;
+        chain.push(parent_name);
+        profile = profile.inherit(parent);
+    }
+    Ok(profile)
+}
+
+/// Reads and parses the config file at `config_path`, along with the files it includes.
+fn read_config(config_path: &Path) -> Result<Config, String> {
+    read_config_value(config_path, &mut Vec::new())?
+        .try_into::<Config>()
+        .map_err(|e| e.to_string())
+}
+
+/// Reads the TOML file at `path` with the files listed in its `include` key merged in,
+/// paths being relative to its directory. The file's own settings win over the included
+/// ones, and later includes over earlier ones. `chain` holds the files being read, to
+/// catch circular includes.
+fn read_config_value(path: &Path, chain: &mut Vec<PathBuf>) -> Result<toml::Value, String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("can't read '{}': {}", path.to_string_lossy(), e))?;
+    if chain.contains(&canonical) {
+        let cycle: Vec<_> = chain
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|path| path.to_string_lossy())
+            .collect();
+        return Err(format!("circular config include: {}", cycle.join(" -> ")));
+    }
+
+    let contents = std::fs::read_to_string(&canonical)
+        .map_err(|e| format!("can't read '{}': {}", path.to_string_lossy(), e))?;
+    let mut value = toml::from_str::<toml::Value>(&contents)
+        .map_err(|e| format!("'{}': {}", path.to_string_lossy(), e))?;
+    let includes = match value.as_table_mut().and_then(|table| table.remove("include")) {
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => return Err(format!("'{}': include must be a list of file paths", path.to_string_lossy())),
+        None => return Ok(value),
+    };
+
+    chain.push(canonical.clone());
+    let dir = canonical.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = toml::Value::Table(Default::default());
+    for include in includes {
+        let include = include
+            .as_str()
+            .ok_or_else(|| format!("'{}': include must be a list of file paths", path.to_string_lossy()))?;
+        merge_toml(&mut merged, read_config_value(&dir.join(include), chain)?);
+    }
+    chain.pop();
+
+    merge_toml(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merges `overrides` into `base`: tables key by key, any other value replacing the base one.
+fn merge_toml(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Tries to parse the file. Logs warnings and return [`None`] if during reading or
+/// parsing errors occur. 
+/// Otherwise, returns [`Some(config)`].
+fn config_from_file(config_path: &Path) -> Option<Config> {
+    read_config(config_path)
+        .map_err(|e| {
+            warn!(
+                "Can't parse config file '{}' error(: {}",
+                config_path.to_string_lossy(),
+                e
+            );
+        })
+        .ok()
+}
+
+/// Lists the problems of `config`: unknown keys, invalid build servers, assignments and
+/// variable names, and profiles that can't be resolved.
+fn config_problems(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    for key in config.unknown.keys() {
+        problems.push(format!("unknown key '{}'", key));
+    }
+    if config.default_command.as_deref().is_some_and(|command| command.trim().is_empty()) {
+        problems.push("default_command is empty".to_owned());
+    }
+    for pattern in &config.copy_back {
+        if pattern.is_empty() || parse_workdir(pattern).is_err() {
+            problems.push(format!("copy_back: '{}' must be a path relative to the workspace, without '..'", pattern));
+        }
+    }
+
+    let mut remotes: Vec<(String, &String)> = Vec::new();
+    remotes.extend(config.remote.iter().map(|remote| ("remote".to_owned(), remote)));
+    for (alias, remote) in &config.aliases {
+        remotes.push((format!("alias '{}'", alias), remote));
+    }
+    remotes.extend(config.pool.iter().map(|remote| ("pool".to_owned(), remote)));
+    remotes.extend(config.fallbacks.iter().map(|remote| ("fallbacks".to_owned(), remote)));
+    for (name, profile) in &config.profiles {
+        remotes.extend(profile.remote.iter().map(|remote| (format!("profile '{}'", name), remote)));
+    }
+    for (place, remote) in remotes {
+        // Aliases are expanded before a server is used.
+        if config.aliases.contains_key(remote) {
+            continue;
+        }
+        if let Err(e) = validate_remote(remote) {
+            problems.push(format!("{}: {}", place, e));
+        }
+    }
+
+    for (name, entries) in &config.env_sets {
+        for entry in entries.iter().filter(|entry| parse_env_assignment(entry).is_err()) {
+            problems.push(format!("env set '{}': invalid assignment '{}' (expected KEY=VALUE)", name, entry));
+        }
+    }
+
+    for (name, profile) in &config.profiles {
+        for key in profile.unknown.keys() {
+            problems.push(format!("profile '{}': unknown key '{}'", name, key));
+        }
+        for entry in profile.build_env.iter().filter(|entry| parse_env_assignment(entry).is_err()) {
+            problems.push(format!("profile '{}': invalid assignment '{}' (expected KEY=VALUE)", name, entry));
+        }
+        for key in profile.unset_env.iter().filter(|key| parse_env_name(key).is_err()) {
+            problems.push(format!("profile '{}': invalid variable name '{}'", name, key));
+        }
+        if let Err(e) = resolve_profile(&config.profiles, name) {
+            problems.push(format!("profile '{}': {}", name, e));
+        }
+    }
+
+    problems.sort();
+    problems
+}
+
+/// Checks every config source of the project, printing the problems found. Exits with an
+/// error if there are any.
+fn check_config(manifest_path: &Path) {
+    let mut cli_metadata = cargo_metadata::MetadataCommand::new();
+    cli_metadata.manifest_path(manifest_path).no_deps();
+    let project_dir = cli_metadata.exec().unwrap().workspace_root;
+
+    let mut failed = false;
+    let mut checked = 0;
+
+    let dotfile = project_dir.join(".remocom-remote");
+    if let Some(remote) = remote_from_dotfile(&dotfile) {
+        checked += 1;
+        if let Err(e) = validate_remote(&remote) {
+            println!("{}: {}", dotfile.to_string_lossy(), e);
+            failed = true;
+        }
+    }
+
+    for path in config_paths(&project_dir) {
+        if !path.exists() {
+            continue;
+        }
+        checked += 1;
+        let problems = match read_config(&path) {
+            Ok(config) => config_problems(&config),
+            Err(e) => vec![e],
+        };
+        for problem in &problems {
+            println!("{}: {}", path.to_string_lossy(), problem);
+        }
+        failed |= !problems.is_empty();
+    }
+
+    if checked == 0 {
+        warn!("No config file found for {:?}", project_dir);
+    }
+    if failed {
+        exit(-30);
+    }
+    info!("Config is valid");
+}
+
+/// Reads the build server from a `.remocom-remote` file: the first line that is neither
+/// empty nor a `#` comment, trimmed. Returns [`None`] if there's no such file or line.
+fn remote_from_dotfile(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+}
+
+/// The build server set for the project itself: its `.remocom-remote` file wins over the
+/// `remote` of the configs, which are in order of precedence.
+fn project_remote(project_dir: &Path, configs: &[Option<Config>]) -> Option<String> {
+    remote_from_dotfile(&project_dir.join(".remocom-remote"))
+        .or_else(|| configs.iter().flatten().find_map(|config| config.remote.clone()))
+}
+
+/// Rejects build server values that can't be an ssh destination, most likely a local
+/// path passed by mistake. Accepts `[user@]host` where host is a name, an IP address or
+/// an ssh_config `Host` alias.
+fn validate_remote(remote: &str) -> Result<(), String> {
+    if remote.starts_with(['.', '/', '~']) || remote.contains(['/', '\\']) {
+        return Err(format!(
+            "'{}' looks like a local path, but the build server must be an ssh destination like user@host",
+            remote
+        ));
+    }
+
+    let (_, host) = split_remote(remote);
+    // Brackets only wrap a whole IPv6 address, e.g. `[fe80::1%eth0]`.
+    let unbracketed = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if unbracketed.contains(['[', ']']) {
+        return Err(format!("'{}' is not a valid ssh destination (misplaced brackets)", remote));
+    }
+    let valid_host = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._:[]%".contains(c));
+    if !valid_host {
+        return Err(format!("'{}' is not a valid ssh destination (expected user@host)", remote));
+    }
+    Ok(())
+}
+
+/// Paths of the config files, highest precedence first: the project's own config, then
+/// the user's one from the XDG config directory if it exists.
+fn config_paths(project_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![project_dir.join("remocom-config.toml")];
+    if let Some(path) = xdg::BaseDirectories::with_prefix("remocom")
+        .ok()
+        .and_then(|base| base.find_config_file("remocom-config.toml"))
+    {
+        paths.push(path);
+    }
+    paths
+}
+
+/// Every build server defined for the project, along with where it's defined: the
+/// `.remocom-remote` file, then each config file in order of precedence.
+fn configured_remotes(project_dir: &Path) -> Vec<(String, String)> {
+    let mut remotes = Vec::new();
+
+    let dotfile = project_dir.join(".remocom-remote");
+    if let Some(remote) = remote_from_dotfile(&dotfile) {
+        remotes.push((remote, dotfile.to_string_lossy().into_owned()));
+    }
+
+    for path in config_paths(project_dir) {
+        if !path.exists() {
+            continue;
+        }
+        let config = match config_from_file(&path) {
+            Some(config) => config,
+            None => continue,
+        };
+        let source = path.to_string_lossy();
+
+        if let Some(remote) = &config.remote {
+            remotes.push((remote.clone(), format!("remote in {}", source)));
+        }
+
+        let mut aliases: Vec<_> = config.aliases.iter().collect();
+        aliases.sort();
+        for (alias, remote) in aliases {
+            remotes.push((remote.clone(), format!("alias '{}' in {}", alias, source)));
+        }
+
+        for remote in &config.pool {
+            remotes.push((remote.clone(), format!("pool in {}", source)));
+        }
+
+        for remote in &config.fallbacks {
+            remotes.push((remote.clone(), format!("fallback in {}", source)));
+        }
+
+        let mut profiles: Vec<_> = config.profiles.iter().collect();
+        profiles.sort_by_key(|(name, _)| name.as_str());
+        for (name, profile) in profiles {
+            if let Some(remote) = &profile.remote {
+                remotes.push((remote.clone(), format!("profile '{}' in {}", name, source)));
+            }
+        }
+    }
+    remotes
+}
+
+/// Prints the build servers defined in each config file, along with where they're defined.
+fn list_remotes(manifest_path: &Path) {
+    let mut cli_metadata = cargo_metadata::MetadataCommand::new();
+    cli_metadata.manifest_path(manifest_path).no_deps();
+    let project_dir = cli_metadata.exec().unwrap().workspace_root;
+
+    for (remote, source) in configured_remotes(&project_dir) {
+        println!("{:<32} {}", remote, source);
+    }
+}
+
+/// Lets the user pick one of `remotes` from a numbered menu. Without a terminal to ask on,
+/// the first one is used. Returns [`None`] if there's nothing to pick from.
+fn choose_remote(remotes: &[(String, String)]) -> Option<String> {
+    use std::io::IsTerminal;
+
+    // Servers defined in several places are only offered once, described by the first.
+    let mut choices: Vec<&(String, String)> = Vec::new();
+    for entry in remotes {
+        if !choices.iter().any(|(remote, _)| *remote == entry.0) {
+            choices.push(entry);
+        }
+    }
+
+    match choices.as_slice() {
+        [] => return None,
+        [(remote, _)] => return Some(remote.clone()),
+        [(remote, _), ..] if !std::io::stdin().is_terminal() => {
+            info!("stdin isn't a terminal, using the first configured build server '{}'", remote);
+            return Some(remote.clone());
+        }
+        _ => {}
+    }
+
+    for (i, (remote, source)) in choices.iter().enumerate() {
+        println!("{:>3}) {:<32} {}", i + 1, remote, source);
+    }
+    loop {
+        print!("Build server [1-{}]: ", choices.len());
+        let _ = std::io::stdout().flush();
+
+        let mut answer = String::new();
+        match std::io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=choices.len()).contains(&n) => return Some(choices[n - 1].0.clone()),
+            _ => println!("Please enter a number between 1 and {}", choices.len()),
+        }
+    }
+}
+
+/// The assignments of the `--env-set` `name`, from the first config that defines it.
+fn find_env_set<'a>(configs: &'a [Option<Config>], name: &str) -> Result<&'a [String], String> {
+    let entries = configs
+        .iter()
+        .flatten()
+        .find_map(|config| config.env_sets.get(name))
+        .ok_or_else(|| format!("No env set '{}' in the config", name))?;
+    if let Some(invalid) = entries.iter().find(|entry| parse_env_assignment(entry).is_err()) {
+        return Err(format!("Invalid assignment '{}' in env set '{}' (expected KEY=VALUE)", invalid, name));
+    }
+    Ok(entries)
+}
+
+/// Prepends the `env_set` assignments to `build_env`, leaving out the variables that
+/// `build_env` assigns itself.
+fn merge_env_set(env_set: &[String], build_env: &str) -> String {
+    let key = |assignment: &str| assignment.split_once('=').map(|(key, _)| key.to_owned());
+    let inline: BTreeSet<_> = build_env.split_whitespace().filter_map(key).collect();
+    let mut merged: Vec<&str> = env_set
+        .iter()
+        .map(String::as_str)
+        .filter(|assignment| key(assignment).is_none_or(|key| !inline.contains(&key)))
+        .collect();
+    merged.push(build_env);
+    merged.join(" ")
+}
+
+/// Replaces `remote` by its target if it's an alias defined in one of the `configs`,
+/// the first config defining it winning. Other values are returned unchanged.
+fn expand_alias(remote: String, configs: &[Option<Config>]) -> String {
+    configs
+        .iter()
+        .flatten()
+        .find_map(|config| config.aliases.get(&remote))
+        .cloned()
+        .unwrap_or(remote)
+}
+
+/// Runs git with `args` in `dir` and returns its trimmed stdout, or [`None`] if git
+/// failed or printed nothing.
+fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if stdout.is_empty() {
+        None
+    } else {
+        Some(stdout)
+    }
+}
+
+/// Lists the uncommitted changes in the git working tree containing `dir`, as printed by
+/// `git status --porcelain`. Returns [`None`] if `dir` isn't in a git repository.
+fn git_dirty_files(dir: &Path) -> Option<Vec<String>> {
+    let output = Command::new("git")
+        .arg("status")
+        .arg("--porcelain")
+        .current_dir(dir)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Returns the name of the branch checked out in `project_dir`, or [`None`] if it can't
+/// be determined (not a git repository, git missing, etc.).
+fn current_git_branch(project_dir: &Path) -> Option<String> {
+    git_output(project_dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+}
+
+/// Waits for `child` to exit, killing it if it's still running after `timeout`.
+/// Returns [`None`] if the child was killed.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Whether a file system event should trigger a rebuild: build output, git internals,
+/// remocom state and (without --transfer-hidden) hidden files aren't uploaded anyway.
+fn is_watched_change(event: &notify::Event, root: &Path, hidden: bool) -> bool {
+    if event.kind.is_access() {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        let rel_path = path.strip_prefix(root).unwrap_or(path);
+        let mut components = rel_path.components().map(|c| c.as_os_str().to_string_lossy());
+        match components.next() {
+            Some(first) if first == "target" || first == ".git" || first.starts_with(".remocom-") => false,
+            Some(first) => hidden || !(first.starts_with('.') || components.any(|c| c.starts_with('.'))),
+            None => false,
+        }
+    })
+}
+
+/// Runs remocom again without the watch flags every time the sources change. Never returns.
+fn watch_and_rebuild(root: &Path, hidden: bool, debounce: Duration, clear: bool) -> ! {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).unwrap_or_else(|e| {
+        error!("Failed to start watching for changes (error: {})", e);
+        exit(-21);
+    });
+    if let Err(e) = notify::Watcher::watch(&mut watcher, root, notify::RecursiveMode::Recursive) {
+        error!("Failed to watch {:?} for changes (error: {})", root, e);
+        exit(-21);
+    }
+
+    let mut args = Vec::new();
+    let mut cli_args = std::env::args_os().skip(1);
+    while let Some(arg) = cli_args.next() {
+        match arg.to_str() {
+            Some("--watch") | Some("--watch-clear") => {}
+            Some("--watch-debounce") => {
+                cli_args.next();
+            }
+            Some(a) if a.starts_with("--watch-debounce=") => {}
+            _ => args.push(arg),
+        }
+    }
+    let exe = std::env::current_exe().unwrap_or_else(|e| {
+        error!("Can't find the remocom executable to rerun (error: {})", e);
+        exit(-21);
+    });
+
+    loop {
+        if clear {
+            print!("\x1B[2J\x1B[H");
+            std::io::stdout().flush().ok();
+        }
+        match Command::new(&exe).args(&args).status() {
+            Ok(status) => info!("Build finished ({}), waiting for changes", status),
+            Err(e) => {
+                error!("Failed to run the build (error: {})", e);
+                exit(-21);
+            }
+        }
+
+        // Files written by the run itself (copied back artifacts, Cargo.lock) don't count.
+        while rx.try_recv().is_ok() {}
+
+        loop {
+            match rx.recv() {
+                Ok(Ok(event)) if is_watched_change(&event, root, hidden) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Error while watching for changes: {}", e),
+                Err(_) => exit(-21),
+            }
+        }
+        // Editors and `git checkout` touch many files at once, wait until it settles.
+        loop {
+            match rx.recv_timeout(debounce) {
+                Ok(_) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => exit(-21),
+            }
+        }
+    }
+}
+
+fn main() {
+    SimpleLogger::new().init().unwrap();
+    info!("Log set");
+
+    let opts = match Opts::from_args() {
+        Opts::ListRemotes { manifest_path } => {
+            list_remotes(&manifest_path);
+            return;
+        }
+        Opts::CheckConfig { manifest_path } => {
+            check_config(&manifest_path);
+            return;
+        }
+        opts => opts,
+    };
+
+    let Opts::Remote {
+        remote,
+        config_profile,
+        pool_strategy,
+        discover,
+        interactive_remote,
+        build_env,
+        env_set,
+        set_env,
+        unset_env,
+        rustc_wrapper,
+        build_env_prefix,
+        redact,
+        remote_env_file,
+        rustup_default,
+        update_toolchain,
+        env,
+        copy_back,
+        copy_back_timeout,
+        copy_back_checksum,
+        copy_back_tar,
+        progress_format,
+        copy_back_root,
+        link_dest,
+        copy_back_as,
+        copy_back_sources,
+        dry_build,
+        copy_back_tests,
+        stream_artifacts,
+        deploy,
+        fingerprint,
+        force,
+        no_copy_lock,
+        diff_lockfile,
+        manifest_path,
+        hidden,
+        repo_root,
+        sync_from_git_root,
+        inplace,
+        normalize_times,
+        compress_min_size,
+        max_file_size,
+        report_savings,
+        parallel_upload,
+        transport_command,
+        toolchain_file,
+        toolchain_filename,
+        setup_script,
+        ping_first,
+        check_clock,
+        remote_build_dir_lock_timeout,
+        stale_lock_after,
+        auto_gc,
+        auto_gc_min_free,
+        preflight_ttl,
+        no_preflight_cache,
+        control_master,
+        control_persist,
+        control_path,
+        ssh_proxy_command,
+        known_hosts,
+        strict_host_keys,
+        ssh_verbose,
+        ssh_debug_log,
+        emit_connect_script,
+        per_branch,
+        abort_if_dirty,
+        allow_dirty,
+        dir_naming,
+        namespace,
+        remote_build_dir,
+        workdir,
+        skip_unchanged,
+        build_log,
+        always_save_log,
+        strip_ansi,
+        stdin_file,
+        rebuild_retries,
+        resync_between_retries,
+        retry_if_output_matches,
+        oom_retry,
+        output_prefix,
+        annotations,
+        quiet_build,
+        record,
+        build_graph,
+        stream_to,
+        stall_warn,
+        heartbeat,
+        emit_metrics,
+        exit_code_map,
+        systemd_run,
+        memory_max,
+        cpu_quota,
+        cgroup,
+        run_as,
+        jobs_fraction,
+        matrix_toolchain,
+        matrix_target,
+        detect_host_triple,
+        use_cross,
+        watch,
+        watch_debounce,
+        watch_clear,
+        warm_index,
+        command,
+        options,
+    } = opts else {
+        unreachable!("other subcommands are handled above");
+    };
+    if let Some(exit_code_map) = exit_code_map {
+        let _ = EXIT_CODE_MAP.set(exit_code_map);
+    }
+    *METRICS.lock().unwrap() = emit_metrics.clone().map(|target| (target, Metrics::default()));
+
+    let mut cli_metadata = cargo_metadata::MetadataCommand::new();
+    cli_metadata.manifest_path(manifest_path).no_deps();
+
+    let project_metadata = cli_metadata.exec().unwrap();
+    let project_dir = project_metadata.workspace_root.clone();
+
+    if abort_if_dirty && !allow_dirty {
+        match git_dirty_files(&project_dir) {
+            Some(dirty) if dirty.is_empty() => {}
+            Some(dirty) => {
+                error!("Working tree has uncommitted changes (use --allow-dirty to build anyway):");
+                for file in dirty {
+                    error!("  {}", file);
+                }
+                exit(-13);
+            }
+            None => {
+                error!("--abort-if-dirty was given but the project isn't in a git repository");
+                exit(-13);
+            }
+        }
+    }
+
+    let config_options: Vec<_> = config_paths(&project_dir)
+        .iter()
+        .map(|path| config_from_file(path))
+        .collect();
+
+    let profile = config_profile.as_ref().map(|name| {
+        let profiles = config_options
+            .iter()
+            .flatten()
+            .map(|config| &config.profiles)
+            .find(|profiles| profiles.contains_key(name))
+            .unwrap_or_else(|| {
+                error!("No profile '{}' in the config", name);
+                exit(-27);
+            });
+        resolve_profile(profiles, name).unwrap_or_else(|e| {
+            error!("Can't use config profile: {}", e);
+            exit(-27);
+        })
+    });
+    let profile = profile.unwrap_or_default();
+    if let Some(invalid) = profile.build_env.iter().find(|entry| parse_env_assignment(entry).is_err()) {
+        error!("Invalid assignment '{}' in the config profile (expected KEY=VALUE)", invalid);
+        exit(-27);
+    }
+    if let Some(invalid) = profile.unset_env.iter().find(|key| parse_env_name(key).is_err()) {
+        error!("Invalid variable name '{}' in the config profile", invalid);
+        exit(-27);
+    }
+    // Options given on the command line follow the ones of the default command.
+    let (command, options) = match command {
+        Some(command) => (Some(command), options),
+        None if warm_index => (None, options),
+        None => {
+            let default_command = config_options
+                .iter()
+                .flatten()
+                .find_map(|config| config.default_command.as_deref())
+                .unwrap_or_else(|| {
+                    error!("No cargo command given and no default_command in the config");
+                    exit(-31);
+                });
+            let mut words = default_command.split_whitespace().map(String::from);
+            let command = words.next().unwrap_or_else(|| {
+                error!("The default_command of the config is empty");
+                exit(-31);
+            });
+            info!("Running the default command: cargo {}", redact_env(default_command, &redact));
+            (Some(command), words.chain(options).collect())
+        }
+    };
+
+    let build_env = merge_env_set(&profile.build_env, &build_env);
+    let unset_env: Vec<String> = profile.unset_env.iter().cloned().chain(unset_env).collect();
+
+    let build_env = match &env_set {
+        Some(name) => {
+            let entries = find_env_set(&config_options, name).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(-25);
+            });
+            merge_env_set(entries, &build_env)
+        }
+        None => build_env,
+    };
+
+    // Options shared by every ssh connection, including the ones rsync opens.
+    let mut ssh_options = Vec::new();
+    if control_master || control_persist.is_some() {
+        ssh_options.extend(vec![
+            "-o".to_owned(),
+            "ControlMaster=auto".to_owned(),
+            "-o".to_owned(),
+            "ControlPath=~/.ssh/remocom-%C".to_owned(),
+            "-o".to_owned(),
+            format!("ControlPersist={}", control_persist.as_deref().unwrap_or("60s")),
+        ]);
+    }
+    if let Some(control_path) = &control_path {
+        // ssh expands `~` and `%` tokens itself, only a literal path can be checked here.
+        let path = control_path.to_string_lossy();
+        let local_path = match path.strip_prefix("~/") {
+            Some(rest) => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(rest)),
+            None => Some(control_path.clone()),
+        };
+        if !path.contains('%') && !local_path.is_some_and(|path| path.exists()) {
+            warn!("ssh control socket {:?} doesn't exist, each ssh connection will be a new one", control_path);
+        }
+        ssh_options.extend(vec![
+            "-o".to_owned(),
+            "ControlMaster=no".to_owned(),
+            "-o".to_owned(),
+            format!("ControlPath={}", path),
+        ]);
+    }
+    if let Some(proxy_command) = &ssh_proxy_command {
+        ssh_options.push("-o".to_owned());
+        ssh_options.push(format!("ProxyCommand={}", proxy_command));
+    }
+    if let Some(known_hosts) = &known_hosts {
+        ssh_options.extend(known_hosts_options(known_hosts, strict_host_keys));
+    }
+    if ssh_verbose {
+        // ssh appends to its log file, start from an empty one so it only covers this run.
+        let debug_log = ssh_debug_log.unwrap_or_else(|| std::env::temp_dir().join("remocom-ssh-debug.log"));
+        if let Err(e) = std::fs::File::create(&debug_log) {
+            error!("Can't create ssh debug log {:?} (error: {})", debug_log, e);
+            exit(-28);
+        }
+        info!("Writing the ssh debug trace to {:?}", debug_log);
+        ssh_options.extend(vec![
+            "-vvv".to_owned(),
+            "-E".to_owned(),
+            debug_log.to_string_lossy().into_owned(),
+        ]);
+    }
+
+    let build_server = remote
+        .first()
+        .cloned()
+        .or_else(|| profile.remote.clone())
+        .or_else(|| {
+            if !interactive_remote {
+                return None;
+            }
+            choose_remote(&configured_remotes(&project_dir))
+        })
+        .or_else(|| {
+            if !discover {
+                return None;
+            }
+            info!("Discovering build servers...");
+            let servers = discover_build_servers(Duration::from_secs(3));
+            for server in &servers {
+                info!("Discovered build server '{}'", server);
+            }
+            if servers.is_empty() {
+                warn!("No build servers discovered, falling back to the configured remote");
+            }
+            servers.into_iter().next()
+        })
+        .or_else(|| project_remote(&project_dir, &config_options))
+    .or_else(|| {
+        let pool: Vec<String> = config_options
+            .iter()
+            .flatten()
+            .map(|config| &config.pool)
+            .find(|pool| !pool.is_empty())?
+            .iter()
+            .map(|server| expand_alias(server.clone(), &config_options))
+            .collect();
+        match pool_strategy {
+            PoolStrategy::First => pool.into_iter().next(),
+            PoolStrategy::LeastLoaded => {
+                let (server, load) = least_loaded_server(&ssh_options, &pool, Duration::from_secs(5))?;
+                info!("Picked '{}' from the pool, load average {:.2}", server, load);
+                Some(server)
+            }
+        }
+    })
+    .map(|server| expand_alias(server, &config_options))
+    .unwrap_or_else(|| {
+        error!("No remote server defined (use remcom-config or --remote flag)");
+        exit(-3);
+    });
+
+    let fallbacks: Vec<String> = if remote.len() > 1 {
+        remote[1..].to_vec()
+    } else {
+        config_options
+            .iter()
+            .flatten()
+            .map(|config| config.fallbacks.clone())
+            .find(|fallbacks| !fallbacks.is_empty())
+            .unwrap_or_default()
+    }
+    .into_iter()
+    .map(|server| expand_alias(server, &config_options))
+    .collect();
+
+    for server in std::iter::once(&build_server).chain(&fallbacks) {
+        if let Err(e) = validate_remote(server) {
+            error!("{}", e);
+            exit(-19);
+        }
+    }
+
+    // Unreachable servers should fail over quickly rather than wait for the TCP timeout.
+    if !fallbacks.is_empty() {
+        ssh_options.extend(vec!["-o".to_owned(), "ConnectTimeout=10".to_owned()]);
+    }
+    let rsync_shell = rsync_shell(&ssh_options);
+    let mut servers = std::iter::once(build_server).chain(fallbacks).peekable();
+
+    // The directory that gets uploaded. Usually the workspace itself, but it can be a
+    // broader root so sibling directories of a nested crate are available remotely.
+    let sync_root = if let Some(repo_root) = repo_root {
+        repo_root.canonicalize().unwrap_or_else(|e| {
+            error!("Can't resolve repository root {:?} (error: {})", repo_root, e);
+            exit(-11);
+        })
+    } else if sync_from_git_root {
+        git_output(&project_dir, &["rev-parse", "--show-toplevel"])
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                error!("--sync-from-git-root was given but the project isn't in a git repository");
+                exit(-11);
+            })
+    } else {
+        project_dir.clone()
+    };
+
+    // `git` and `canonicalize` resolve symlinks, the cargo metadata path doesn't necessarily.
+    let project_rel_path = project_dir
+        .strip_prefix(&sync_root)
+        .ok()
+        .map(Path::to_path_buf)
+        .or_else(|| {
+            let canonical = project_dir.canonicalize().ok()?;
+            canonical.strip_prefix(&sync_root).ok().map(Path::to_path_buf)
+        })
+        .unwrap_or_else(|| {
+            error!(
+                "Project {:?} is not inside the upload root {:?}",
+                project_dir, sync_root
+            );
+            exit(-11);
+        });
+
+    if watch {
+        watch_and_rebuild(&sync_root, hidden, Duration::from_millis(watch_debounce), watch_clear);
+    }
+
+    let build_path = if let Some(build_dir) = remote_build_dir {
+        // --dir-naming has a default, so it can't simply conflict with --build-dir.
+        if dir_naming != DirNaming::Hash {
+            warn!("--dir-naming has no effect with --build-dir");
+        }
+        build_dir
+    } else {
+        let namespace = namespace
+            .or_else(|| std::env::var("USER").ok())
+            .or_else(|| std::env::var("USERNAME").ok())
+            .unwrap_or_default();
+        let branch = if per_branch {
+            let branch = current_git_branch(&project_dir);
+            match &branch {
+                Some(branch) => info!("Using build directory for branch '{}'", branch),
+                None => warn!("Can't determine the current git branch, using the shared build directory"),
+            }
+            branch
+        } else {
+            None
+        };
+        let name = project_metadata
+            .root_package()
+            .or_else(|| project_metadata.packages.first())
+            .map(|package| package.name.clone())
+            .or_else(|| project_dir.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        default_build_path(&sync_root, &namespace, branch.as_deref(), dir_naming, &name)
+    };
+    // Where the workspace lives inside the build path, which is where cargo runs.
+    let remote_project_path = format!("{}{}", build_path, project_rel_path.to_string_lossy());
+    // rustup follows the toolchain file, pinning a default on top of it would be pointless.
+    let project_toolchain = detect_toolchain_file(&project_dir, &sync_root, toolchain_filename.as_deref());
+    if let Some(name) = &project_toolchain {
+        info!("Using the toolchain of the project's {}", name);
+    }
+    let toolchain_pinned = toolchain_file.is_some() || project_toolchain.is_some();
+    let toolchain_file_name = toolchain_filename
+        .clone()
+        .or_else(|| project_toolchain.clone())
+        .unwrap_or_else(|| "rust-toolchain.toml".to_owned());
+
+    let remote_workdir = remote_workdir(&build_path, workdir.as_deref(), &remote_project_path);
+
+    // Only a convenience for dashboards: without it, or once it fails, the build goes on.
+    let mut output_stream = stream_to.as_ref().and_then(|url| match tungstenite::connect(url.as_str()) {
+        Ok((socket, _)) => {
+            info!("Streaming the build output to {}", url);
+            Some(socket)
+        }
+        Err(e) => {
+            warn!("Can't connect to {} to stream the build output (error: {})", url, e);
+            None
+        }
+    });
+    let record_start = Instant::now();
+    let record_started_at = std::time::SystemTime::now();
+    let mut cast_events = Vec::new();
+
+    // The upload and build start over on the next server when one can't be connected to.
+    'servers: loop {
+        let build_server = servers
+            .next()
+            .unwrap_or_else(|| unreachable!("the loop ends with the last server"));
+        let rsync_server = rsync_destination(&build_server);
+
+        if let Some(script_path) = &emit_connect_script {
+            write_connect_script(script_path, &ssh_options, &build_server, &remote_project_path)
+                .unwrap_or_else(|e| {
+                    error!("Failed to write connect script {:?} (error: {})", script_path, e);
+                    exit(-22);
+                });
+            info!("Wrote connect script to {:?}", script_path);
+        }
+
+        let mut preflight = PreflightCache::load(
+            &build_server,
+            &ssh_options,
+            Duration::from_secs(preflight_ttl),
+            !no_preflight_cache,
+        );
+
+        if ping_first {
+            let reachable = preflight.check("ping", || {
+                info!("Checking build server is reachable");
+                server_reachable(&ssh_options, &build_server, true)
+            });
+
+            if !reachable && servers.peek().is_some() {
+                warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                continue 'servers;
+            }
+            if !reachable {
+                error!("Cannot connect to build server '{}'", build_server);
+                exit(-9);
+            }
+        }
+
+        if check_clock {
+            match remote_clock_skew(&ssh_options, &build_server) {
+                Some(skew) if skew.abs() > MAX_CLOCK_SKEW.as_secs_f64() => warn!(
+                    "The build server's clock is {:.0}s {} the local one. rsync may upload unchanged files \
+                     or miss changed ones, consider --normalize-times (compares by checksum) or syncing the clocks",
+                    skew.abs(),
+                    if skew > 0.0 { "ahead of" } else { "behind" }
+                ),
+                Some(skew) => info!("Build server clock skew {:.0}s", skew),
+                None => warn!("Can't read the build server's clock, skipping --check-clock"),
+            }
+        }
+
+        // Failed runs release the lock as they exit, runs that are killed leave it behind and
+        // --stale-lock-after cleans those up.
+        let build_lock = format!("{}.remocom-lock", build_path);
+        if let Some(lock_timeout) = remote_build_dir_lock_timeout {
+            if !acquire_build_lock(
+                &ssh_options,
+                &build_server,
+                &build_path,
+                &build_lock,
+                Duration::from_secs(lock_timeout),
+                stale_lock_after,
+            ) {
+                error!("Timed out waiting for the build directory lock {}", build_lock);
+                exit(-23);
+            }
+            let (ssh_options, build_server, build_lock) = (ssh_options.clone(), build_server.clone(), build_lock.clone());
+            at_exit(move || release_build_lock(&ssh_options, &build_server, &build_lock));
+        }
+
+        if auto_gc {
+            let current_dir = build_path.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+            collect_build_dirs(&ssh_options, &build_server, current_dir, auto_gc_min_free * 1024 * 1024);
+        }
+
+        if warm_index {
+            // cargo needs every package's manifest and the files its targets are found by.
+            info!("Transferring manifests to your build server.");
+            let mut rsync_manifests = Command::new("rsync");
+            rsync_manifests
+                .arg("-a")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg("--compress")
+                .arg("--prune-empty-dirs")
+                .arg("--exclude=/target/")
+                .arg("--exclude=.git/");
+            let patterns = ["*/", "Cargo.toml", "Cargo.lock"];
+            for pattern in patterns.iter().chain(TOOLCHAIN_FILE_NAMES).chain(toolchain_filename.as_deref().iter()) {
+                rsync_manifests.arg(format!("--include={}", pattern));
+            }
+            for pattern in target_source_patterns(&project_metadata, &sync_root) {
+                rsync_manifests.arg(format!("--include={}", pattern));
+            }
+            let uploaded = rsync_manifests
+                .arg("--exclude=*")
+                .arg("--rsync-path")
+                .arg(remote_rsync_path(&build_path))
+                .arg(format!("{}/", sync_root.to_string_lossy()))
+                .arg(format!("{}:{}", rsync_server, build_path))
+                .stdin(Stdio::inherit())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !uploaded {
+                error!("Failed to transfer the manifests to build server");
+                exit(-4);
+            }
+
+            info!("Fetching dependencies on the build server...");
+            let rustup_command = if toolchain_pinned {
+                String::new()
+            } else {
+                format!("rustup default {}; ", rustup_default)
+            };
+            let fetch_status = ssh_command(&ssh_options, &build_server)
+                .arg(format!(
+                    "source {}; {}cd {}; cargo fetch",
+                    env, rustup_command, remote_project_path
+                ))
+                .stdin(Stdio::null())
+                .status()
+                .unwrap_or_else(|e| {
+                    error!("Failed to run cargo fetch remotely (error: {})", e);
+                    exit(-5);
+                });
+            run_cleanups();
+            if !fetch_status.success() {
+                exit(fetch_status.code().unwrap_or(1));
+            }
+            flush_metrics(0);
+            return;
+        }
+        let command = command
+            .clone()
+            .unwrap_or_else(|| unreachable!("there's a default command without --warm-index"));
+
+        // A custom transport doesn't report what it transferred the way rsync does.
+        if transport_command.is_some()
+            && (parallel_upload > 1
+                || compress_min_size.is_some()
+                || max_file_size.is_some()
+                || report_savings
+                || skip_unchanged)
+        {
+            warn!(
+                "--parallel-upload, --compress-min-size, --max-file-size, --report-savings and --skip-unchanged \
+                 don't apply to --transport-command"
+            );
+        }
+        let skip_unchanged = skip_unchanged && transport_command.is_none();
+        let compress_min_size = compress_min_size.filter(|_| transport_command.is_none());
+        let transport = |template: &str| {
+            let mut transport = Command::new("sh");
+            transport
+                .arg("-c")
+                .arg(expand_transport_command(
+                    template,
+                    &format!("{}/", sync_root.to_string_lossy()),
+                    &build_server,
+                    &build_path,
+                ))
+                .stdin(Stdio::inherit())
+                .stderr(Stdio::inherit());
+            transport
+        };
+
+        // Transfers the project to the user's build server
+        let upload_options = || {
+            upload_rsync(
+                &rsync_shell,
+                &build_path,
+                toolchain_filename.as_deref(),
+                hidden,
+                normalize_times,
+                inplace,
+                max_file_size,
+            )
+        };
+
+        let upload_command = || {
+            let mut rsync_to = upload_options();
+            rsync_to
+                .arg(format!("{}/", sync_root.to_string_lossy()))
+                .arg(format!("{}:{}", rsync_server, build_path));
+            rsync_to
+        };
+
+        // Estimates how much data the upload will send, without transferring anything.
+        let estimate_upload_size = || {
+            let output = upload_command()
+                .arg("--dry-run")
+                .arg("--stats")
+                .output()
+                .ok()?;
+            rsync_stat(&String::from_utf8_lossy(&output.stdout), "Total transferred file size")
+        };
+
+        // For small incremental uploads the compression setup isn't worth it.
+        let compress = match compress_min_size {
+            Some(min_size) => match estimate_upload_size() {
+                Some(size) if size < min_size => {
+                    info!("Only {} bytes to transfer, uploading uncompressed", size);
+                    false
+                }
+                Some(_) => true,
+                None => {
+                    warn!("Can't estimate the upload size, compressing the upload");
+                    true
+                }
+            },
+            None => true,
+        };
+
+        let transfer_options = |rsync_to: &mut Command| {
+            if compress {
+                rsync_to.arg("--compress");
+            }
+
+            if emit_metrics.is_some() || report_savings {
+                rsync_to.arg("--stats");
+            }
+
+            if skip_unchanged {
+                rsync_to.arg("--itemize-changes");
+            }
+        };
+
+        info!("Sources are being transferred to your build server.");
+        let upload_start = Instant::now();
+
+        let upload_output = if let Some(template) = &transport_command {
+            let (status, upload_output) = output_passthrough(&mut transport(template)).unwrap_or_else(|e| {
+                error!("Failed to run the transport command (error: {})", e);
+                exit(-4);
+            });
+            if connection_failed(status) && servers.peek().is_some() {
+                warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                run_cleanups();
+                continue 'servers;
+            }
+            if !status.success() {
+                error!("The transport command failed ({})", status);
+                exit(-4);
+            }
+            upload_output
+        } else if parallel_upload > 1 {
+            // The top level goes first without recursing: it creates the directories, sends
+            // the top-level files and deletes the entries removed locally. Each directory is
+            // then synced, deletes included, by exactly one of the parallel transfers.
+            let mut top_level = upload_options();
+            transfer_options(&mut top_level);
+            top_level
+                .arg("--no-recursive")
+                .arg("--dirs")
+                .arg(format!("{}/", sync_root.to_string_lossy()))
+                .arg(format!("{}:{}", rsync_server, build_path));
+            let (status, mut upload_output) = output_passthrough(&mut top_level).unwrap_or_else(|e| {
+                error!("Failed to transfer project to build server (error: {})", e);
+                exit(-4);
+            });
+            if connection_failed(status) && servers.peek().is_some() {
+                warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                run_cleanups();
+                continue 'servers;
+            }
+            if !status.success() {
+                error!("Failed to transfer the top level of the project to build server ({})", status);
+                exit(-4);
+            }
+
+            let mut dirs: Vec<PathBuf> = std::fs::read_dir(&sync_root)
+                .map(|entries| {
+                    entries
+                        .flatten()
+                        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                        .map(|entry| entry.path())
+                        .filter(|path| {
+                            let name = path.file_name().unwrap_or_default().to_string_lossy();
+                            (hidden || !name.starts_with('.')) && !name.starts_with(".remocom-")
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            dirs.sort();
+
+            let streams: Vec<_> = partition_round_robin(dirs, parallel_upload)
+                .into_iter()
+                .map(|group| {
+                    let mut rsync_to = upload_options();
+                    transfer_options(&mut rsync_to);
+                    rsync_to
+                        .args(&group)
+                        .arg(format!("{}:{}", rsync_server, build_path))
+                        .stdout(Stdio::piped());
+                    let child = rsync_to.spawn().unwrap_or_else(|e| {
+                        error!("Failed to transfer project to build server (error: {})", e);
+                        exit(-4);
+                    });
+                    thread::spawn(move || child.wait_with_output())
+                })
+                .collect();
+
+            let mut failed = false;
+            let mut disconnected = false;
+            for stream in streams {
+                match stream.join() {
+                    Ok(Ok(output)) => {
+                        failed |= !output.status.success();
+                        disconnected |= connection_failed(output.status);
+                        upload_output.push_str(&String::from_utf8_lossy(&output.stdout));
+                    }
+                    _ => failed = true,
+                }
+            }
+            if disconnected && servers.peek().is_some() {
+                warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                run_cleanups();
+                continue 'servers;
+            }
+            // Building a partially synced tree would give misleading results.
+            if failed {
+                error!("Some of the parallel transfers to the build server failed");
+                exit(-4);
+            }
+            upload_output
+        } else {
+            let mut rsync_to = upload_command();
+            rsync_to.arg("--info=progress2");
+            transfer_options(&mut rsync_to);
+
+            let (status, upload_output) = output_passthrough(&mut rsync_to).unwrap_or_else(|e| {
+                error!("Failed to transfer project to build server (error: {})", e);
+                exit(-4);
+            });
+            if connection_failed(status) && servers.peek().is_some() {
+                warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                run_cleanups();
+                continue 'servers;
+            }
+            upload_output
+        };
+
+        let oversized = oversized_files(&upload_output);
+        if !oversized.is_empty() {
+            warn!("Skipped {} file(s) larger than {} bytes:", oversized.len(), max_file_size.unwrap_or_default());
+            for file in oversized {
+                warn!("  {}", file);
+            }
+        }
+
+        let upload_bytes = rsync_stat(&upload_output, "Total bytes sent");
+        update_metrics(|metrics| {
+            metrics.upload_duration = Some(upload_start.elapsed());
+            metrics.upload_bytes = upload_bytes;
+        });
+
+        if report_savings && transport_command.is_none() {
+            match (upload_bytes, rsync_stat(&upload_output, "Total file size")) {
+                (Some(sent), Some(total)) if total > 0 => {
+                    let percent = sent as f64 * 100.0 / total as f64;
+                    info!(
+                        "Synced {} of {} ({:.1}%), incremental sync saved {:.1}%",
+                        human_bytes(sent),
+                        human_bytes(total),
+                        percent,
+                        (100.0 - percent).max(0.0)
+                    );
+                }
+                (Some(sent), Some(_)) => info!("Synced {} of an empty tree", human_bytes(sent)),
+                _ => warn!("Can't report the sync savings, rsync didn't print its stats"),
+            }
+        }
+
+        // The override is sent after the main upload so its `--delete` doesn't remove it.
+        if let Some(toolchain_file) = &toolchain_file {
+            info!("Transferring toolchain override {:?}", toolchain_file);
+            Command::new("rsync")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg("--compress")
+                .arg(toolchain_file)
+                .arg(format!("{}:{}/{}", rsync_server, remote_project_path, toolchain_file_name))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit())
+                .output()
+                .unwrap_or_else(|e| {
+                    error!("Failed to transfer toolchain file to build server (error: {})", e);
+                    exit(-8);
+                });
+        }
+    
+        // Like the other remocom files it's excluded from the upload, so `--delete` keeps it.
+        let remote_env_path = format!("{}.remocom-env", build_path);
+        if let Some(remote_env_file) = &remote_env_file {
+            info!("Transferring environment file {:?}", remote_env_file);
+            let transferred = Command::new("rsync")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg("--chmod=600")
+                .arg(remote_env_file)
+                .arg(format!("{}:{}", rsync_server, remote_env_path))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !transferred {
+                error!("Failed to transfer environment file to build server");
+                exit(-20);
+            }
+        }
+
+        if let Some(setup_script) = &setup_script {
+            let remote_script = format!("{}.remocom-setup.sh", build_path);
+            let marker = format!("{}.remocom-setup-done", build_path);
+
+            Command::new("rsync")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg(setup_script)
+                .arg(format!("{}:{}", rsync_server, remote_script))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .output()
+                .unwrap_or_else(|e| {
+                    error!("Failed to transfer setup script to build server (error: {})", e);
+                    exit(-16);
+                });
+
+            let setup_command = format!(
+                "source {}; cd {}; if [ -e {marker} ]; then exit 0; fi; echo 'Running setup script'; bash {} && touch {marker}",
+                env,
+                remote_project_path,
+                remote_script,
+                marker = marker
+            );
+            let status = ssh_command(&ssh_options, &build_server)
+                .arg("-t")
+                .arg(setup_command)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit())
+                .status()
+                .unwrap_or_else(|e| {
+                    error!("Failed to run setup script on build server (error: {})", e);
+                    exit(-16);
+                });
+            if !status.success() {
+                error!("Setup script failed on the build server, it will run again next time");
+                exit(-16);
+            }
+        }
+
+        // The exit code of the last build in this directory, so a failure isn't skipped into a pass.
+        let last_status_path = format!("{}.remocom-last-status", build_path);
+        if skip_unchanged && count_itemized_changes(&upload_output) == 0 {
+            let last_status = ssh_command(&ssh_options, &build_server)
+                .arg(format!("cat {} 2>/dev/null", shell_escape_path(&last_status_path)))
+                .stdin(Stdio::null())
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+                .unwrap_or_default();
+            if last_status == "0" {
+                info!("No changes, skipping build.");
+                run_cleanups();
+                flush_metrics(0);
+                return;
+            }
+            info!("No changes, but the last build didn't succeed, building again.");
+        }
+
+        log::info!("Build ENV: {:?}", redact_env(&build_env, &redact));
+        log::info!("Environment profile: {:?}", env);
+        log::info!("Build path: {:?}", remote_project_path);
+
+        let mut cargo_prefix = None;
+        if systemd_run {
+            if preflight.check("command systemd-run", || {
+                remote_has_command(&ssh_options, &build_server, "systemd-run")
+            }) {
+                cargo_prefix = Some(systemd_run_prefix(memory_max.as_deref(), cpu_quota.as_deref()));
+            } else {
+                warn!("systemd-run is not available on the build server, building without resource limits");
+            }
+        } else if memory_max.is_some() || cpu_quota.is_some() {
+            warn!("--memory-max and --cpu-quota only apply with --systemd-run");
+        }
+
+        // Shell statements run right before cargo.
+        let mut build_setup = unset_statements(&unset_env);
+        if skip_unchanged {
+            // An interrupted build must not pass for a successful one.
+            build_setup.push_str(&format!("rm -f {}; ", shell_escape_path(&last_status_path)));
+        }
+        if remote_env_file.is_some() {
+            build_setup.push_str(&format!("source {}; ", remote_env_path));
+        }
+
+        // Moving the remote shell into the cgroup places everything it spawns there too.
+        if let Some(cgroup) = &cgroup {
+            let procs = format!("{}/cgroup.procs", cgroup.trim_end_matches('/'));
+            let writable = preflight.check(&format!("writable {}", procs), || {
+                ssh_command(&ssh_options, &build_server)
+                    .arg(format!("test -w {}", shell_escape_path(&procs)))
+                    .stdin(Stdio::null())
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false)
+            });
+            if writable {
+                build_setup.push_str(&format!("echo $$ > {}; ", shell_escape_path(&procs)));
+            } else {
+                warn!("{} is not writable on the build server, building outside the cgroup", procs);
+            }
+        }
+
+        if let Some(wrapper) = &rustc_wrapper {
+            if !preflight.check(&format!("command {}", wrapper), || {
+                remote_has_command(&ssh_options, &build_server, wrapper)
+            }) {
+                error!("rustc wrapper '{}' was not found on the build server", wrapper);
+                exit(-15);
+            }
+            if build_env.contains("RUSTC_WRAPPER=") {
+                warn!("RUSTC_WRAPPER from --build-env is overridden by --rustc-wrapper");
+            }
+        }
+
+        if use_cross {
+            if !preflight.check("command cross", || remote_has_command(&ssh_options, &build_server, "cross")) {
+                error!("cross was not found on the build server (cargo install cross)");
+                exit(-29);
+            }
+            if cargo_option_value(&options, "--target").is_none() && matrix_target.is_empty() {
+                warn!("--use-cross without --target builds for the build server's own host");
+            }
+        }
+        let cargo_program = if use_cross { "cross" } else { "cargo" };
+
+        // It's opened again for each build attempt, a bad path fails before any secret is uploaded.
+        if let Some(path) = &stdin_file {
+            if let Err(e) = std::fs::File::open(path) {
+                error!("Can't open stdin file {:?} (error: {})", path, e);
+                exit(-14);
+            }
+        }
+
+        // Secrets never go on a command line, where they'd show in process listings on both
+        // ends: they're written through ssh's stdin to a private file the build sources.
+        let (build_env, secrets) = resolve_keyring_env(&build_env).unwrap_or_else(|e| {
+            error!("Can't resolve build environment {}", e);
+            exit(-18);
+        });
+        let remote_secrets = format!("{}.remocom-secrets", build_path);
+        if !secrets.is_empty() {
+            // Removed however the run ends, a failed transfer may have left part of the file.
+            let remove_secrets = format!("rm -f {}", shell_escape_path(&remote_secrets));
+            let (cleanup_options, cleanup_server) = (ssh_options.clone(), build_server.clone());
+            at_exit(move || {
+                let _ = ssh_command(&cleanup_options, &cleanup_server)
+                    .arg(remove_secrets)
+                    .stdin(Stdio::null())
+                    .status();
+            });
+
+            let stored = ssh_command(&ssh_options, &build_server)
+                .arg(format!("umask 077 && cat > {}", remote_secrets))
+                .stdin(Stdio::piped())
+                .spawn()
+                .and_then(|mut child| {
+                    let mut stdin = child.stdin.take().expect("stdin is piped");
+                    for (key, secret) in &secrets {
+                        writeln!(stdin, "export {}={}", key, shell_escape(secret))?;
+                    }
+                    drop(stdin);
+                    child.wait()
+                })
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !stored {
+                error!("Failed to transfer keyring secrets to the build server");
+                exit(-18);
+            }
+            build_setup.push_str(&format!("source {}; ", remote_secrets));
+        }
+
+        if let Some(label) = &build_env_prefix {
+            let mut injected: Vec<(String, &str)> = build_env
+                .split_whitespace()
+                .filter_map(|assignment| assignment.split_once('='))
+                .map(|(key, _)| (key.to_owned(), "--build-env"))
+                .collect();
+            injected.extend(secrets.iter().map(|(key, _)| (key.clone(), "keyring")));
+            injected.extend(
+                set_env
+                    .iter()
+                    .filter_map(|assignment| assignment.split_once('='))
+                    .map(|(key, _)| (key.to_owned(), "--set-env")),
+            );
+            if rustc_wrapper.is_some() {
+                injected.push(("RUSTC_WRAPPER".to_owned(), "--rustc-wrapper"));
+            }
+
+            info!("{} {} variable(s) added to the build environment", label, injected.len());
+            for (key, source) in injected {
+                info!("{} {}=<redacted> (from {})", label, key, source);
+            }
+        }
+
+        if let Some(user) = &run_as {
+            warn!(
+                "--run-as needs passwordless sudo to '{}', and '{}' must be able to write to {}",
+                user, user, build_path
+            );
+        }
+
+        if !set_env.is_empty() {
+            warn!("--set-env only works if the build server's sshd accepts these variables (AcceptEnv)");
+        }
+
+        // Kept next to the sources, hidden so the default upload never deletes it.
+        let remote_build_log = format!("{}.remocom-build.log", build_path);
+
+        // An explicit --target wins, cargo would reject a second one.
+        let host_triple = if detect_host_triple && cargo_option_value(&options, "--target").is_some() {
+            info!("Not detecting the build server's host triple, the cargo options set --target");
+            None
+        } else if detect_host_triple {
+            let triple = detect_remote_triple(&ssh_options, &build_server, &env);
+            match &triple {
+                Some(triple) => info!("Build server host triple: {}", triple),
+                None => warn!("Can't detect the build server's host triple, using cargo's default target"),
+            }
+            triple
+        } else {
+            None
+        };
+
+        let jobs = jobs_fraction.and_then(|fraction| {
+            if options.iter().any(|option| option == "-j" || option.starts_with("--jobs")) {
+                warn!("--jobs-fraction is ignored, the cargo options already set the number of jobs");
+                return None;
+            }
+            match remote_cpu_count(&ssh_options, &build_server) {
+                Some(cpus) => {
+                    let jobs = ((fraction * f64::from(cpus)).ceil() as u32).max(1);
+                    info!("Building with {} of the build server's {} CPUs", jobs, cpus);
+                    Some(jobs)
+                }
+                None => {
+                    warn!("Can't get the build server's CPU count, not limiting cargo jobs");
+                    None
+                }
+            }
+        });
+
+        let oom_retry = oom_retry && {
+            let jobs_set = options.iter().any(|option| option == "-j" || option.starts_with("--jobs"));
+            if jobs_set {
+                warn!("--oom-retry is ignored, the cargo options already set the number of jobs");
+            }
+            !jobs_set
+        };
+
+        if retry_if_output_matches.is_some() && rebuild_retries == 0 {
+            warn!("--retry-if-output-matches has no effect without --rebuild-retries");
+        }
+
+        let mut variants = build_matrix(&matrix_toolchain, &matrix_target);
+        for variant in &mut variants {
+            if variant.target.is_none() {
+                variant.target = host_triple.clone();
+            }
+        }
+        let mut results = Vec::new();
+
+        if update_toolchain {
+            // A toolchain file pins its own toolchain, unless the matrix overrides it.
+            let mut channels: Vec<&str> = variants
+                .iter()
+                .filter_map(|variant| match (&variant.toolchain, toolchain_pinned) {
+                    (Some(toolchain), _) => Some(toolchain.as_str()),
+                    (None, true) => None,
+                    (None, false) => Some(rustup_default.as_str()),
+                })
+                .collect();
+            channels.sort();
+            channels.dedup();
+            if channels.is_empty() {
+                warn!("--update-toolchain doesn't update the toolchain of a toolchain file");
+            }
+            for channel in channels {
+                info!("Updating the {} toolchain on the build server...", channel);
+                let updated = ssh_command(&ssh_options, &build_server)
+                    .arg(format!("source {}; rustup update {}", env, shell_escape(channel)))
+                    .stdin(Stdio::null())
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false);
+                if !updated {
+                    warn!("Failed to update the {} toolchain, building with the installed one", channel);
+                    continue;
+                }
+                let version = ssh_command(&ssh_options, &build_server)
+                    .arg(format!("source {}; rustc +{} --version", env, shell_escape(channel)))
+                    .stdin(Stdio::null())
+                    .output()
+                    .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+                    .unwrap_or_default();
+                info!("The {} toolchain is {}", channel, version);
+            }
+        }
+
+        // These need cargo's JSON messages, which are then rendered as the usual output.
+        // cargo-nextest has no such messages, its test binaries are packed in an archive instead.
+        let nextest = command == "nextest";
+        let parse_messages = !nextest && (annotations.is_some() || copy_back_tests || quiet_build || stream_artifacts);
+        let mut test_executables = BTreeSet::new();
+        let mut streamed_artifacts = BTreeSet::new();
+        let mut artifact_transfers = Vec::new();
+        if nextest {
+            if annotations.is_some() {
+                warn!("--annotations doesn't support cargo nextest, no annotations will be emitted");
+            }
+            if stream_artifacts {
+                warn!("--stream-artifacts doesn't support cargo nextest, nothing will be streamed back");
+            }
+            if copy_back_tests {
+                match (options.first().map(String::as_str), cargo_option_value(&options, "--archive-file")) {
+                    (Some("archive"), Some(archive)) if archive.starts_with('/') => {
+                        test_executables.insert(archive);
+                    }
+                    (Some("archive"), Some(archive)) => {
+                        test_executables.insert(format!("{}/{}", remote_project_path.trim_end_matches('/'), archive));
+                    }
+                    _ => warn!("--copy-back-tests with cargo nextest needs `nextest archive --archive-file <file>`"),
+                }
+            }
+        }
+
+        let mut graph_script = None;
+        for variant in &variants {
+            // A toolchain override file takes precedence, so don't pin a default on top of it,
+            // unless the matrix explicitly asks for a toolchain.
+            let rustup_command = match (&variant.toolchain, toolchain_pinned) {
+                (Some(toolchain), _) => format!("rustup default {}; ", toolchain),
+                (None, true) => String::new(),
+                (None, false) => format!("rustup default {}; ", rustup_default),
+            };
+
+            let mut variant_env = expand_build_env(
+                &build_env,
+                &build_path,
+                &remote_project_path,
+                variant.target.as_deref(),
+            );
+            if variant_env.contains("{target}") {
+                warn!("{{target}} in --build-env is only replaced when building for a --matrix-target");
+            }
+            // Assigned last so it wins over a RUSTC_WRAPPER from --build-env.
+            if let Some(wrapper) = &rustc_wrapper {
+                variant_env.push_str(&format!(" RUSTC_WRAPPER={}", shell_escape(wrapper)));
+            }
+            let build_command = BuildCommand {
+                env: &env,
+                rustup_command: &rustup_command,
+                workdir: &remote_workdir,
+                setup: &build_setup,
+                program: cargo_program,
+                command: &command,
+                options: &options,
+                message_format_json: parse_messages,
+                target: variant.target.as_deref(),
+                unit_graph: false,
+                prefix: cargo_prefix.as_deref(),
+                build_env: &variant_env,
+                log: build_log.as_ref().map(|_| (remote_build_log.as_str(), !results.is_empty())),
+                run_as: run_as.as_deref(),
+            };
+            // Of a matrix, the unit graph is the first build's.
+            if build_graph.is_some() && graph_script.is_none() {
+                graph_script = Some(
+                    BuildCommand {
+                        message_format_json: false,
+                        unit_graph: true,
+                        log: None,
+                        ..build_command
+                    }
+                    .script(None),
+                );
+            }
+            let mut variant_jobs = jobs;
+
+            if variants.len() > 1 {
+                info!(
+                    "Starting build process for toolchain {} target {}...",
+                    variant.toolchain.as_deref().unwrap_or(&rustup_default),
+                    variant.target.as_deref().unwrap_or("default")
+                );
+            } else {
+                info!("Starting build process...");
+            }
+            let build_start = Instant::now();
+            let heartbeat = heartbeat.map(|seconds| start_heartbeat(Duration::from_secs(seconds)));
+            let mut attempt = 0;
+            let status = loop {
+                attempt += 1;
+                let mut ssh_build = ssh_command(&ssh_options, &build_server);
+                for assignment in &set_env {
+                    ssh_build.arg("-o").arg(format!("SetEnv={}", assignment));
+                }
+                // Scripted input doesn't go through a terminal, so there's no pty to allocate.
+                let stdin = match &stdin_file {
+                    Some(path) => Stdio::from(std::fs::File::open(path).unwrap_or_else(|e| {
+                        error!("Can't open stdin file {:?} (error: {})", path, e);
+                        exit(-14);
+                    })),
+                    None => {
+                        ssh_build.arg("-t");
+                        Stdio::inherit()
+                    }
+                };
+                ssh_build.arg(build_command.script(variant_jobs)).stdin(stdin);
+                let last_output = Arc::new(Mutex::new(Instant::now()));
+                let watchdog = stall_warn
+                    .map(|seconds| watch_for_stalls(Arc::clone(&last_output), Duration::from_secs(seconds)));
+                let piped = output_prefix.is_some()
+                    || parse_messages
+                    || quiet_build
+                    || record.is_some()
+                    || stall_warn.is_some()
+                    || oom_retry
+                    || retry_if_output_matches.is_some()
+                    || output_stream.is_some();
+                let mut sigkilled = false;
+                let mut output_matched = false;
+                let status = if piped {
+                    let mut print = |line: &str, is_stderr: bool| {
+                        let line = match &output_prefix {
+                            Some(prefix) => format!("{} {}", prefix, line),
+                            None => line.to_owned(),
+                        };
+                        if record.is_some() {
+                            cast_events.push((record_start.elapsed().as_secs_f64(), format!("{}\r\n", line)));
+                        }
+                        if let Some(socket) = &mut output_stream {
+                            let message = serde_json::json!({
+                                "stream": if is_stderr { "stderr" } else { "stdout" },
+                                "line": line,
+                            });
+                            if let Err(e) = socket.send(tungstenite::Message::text(message.to_string())) {
+                                warn!("Stopped streaming the build output (error: {})", e);
+                                output_stream = None;
+                            }
+                        }
+                        if is_stderr {
+                            eprintln!("{}", line);
+                        } else {
+                            println!("{}", line);
+                        }
+                    };
+                    run_piped(&mut ssh_build, |line, is_stderr| {
+                        *last_output.lock().unwrap() = Instant::now();
+                        // cargo reports a rustc killed by the OOM killer as `(signal: 9, SIGKILL: kill)`.
+                        if oom_retry && line.contains("SIGKILL") {
+                            sigkilled = true;
+                        }
+                        if let Some(pattern) = &retry_if_output_matches {
+                            output_matched |= pattern.is_match(line);
+                        }
+                        if quiet_build && is_cargo_progress(line) {
+                            return;
+                        }
+                        if is_stderr || !parse_messages {
+                            return print(line, is_stderr);
+                        }
+                        // The JSON messages replace cargo's usual output, print it from them.
+                        match cargo_metadata::Message::parse_stream(line.as_bytes()).next() {
+                            Some(Ok(cargo_metadata::Message::CompilerMessage(message))) => {
+                                use cargo_metadata::diagnostic::DiagnosticLevel;
+                                let shown = !quiet_build
+                                    || matches!(
+                                        message.message.level,
+                                        DiagnosticLevel::Error | DiagnosticLevel::Warning | DiagnosticLevel::Ice
+                                    );
+                                if let (true, Some(rendered)) = (shown, &message.message.rendered) {
+                                    for rendered_line in rendered.trim_end().lines() {
+                                        print(rendered_line, false);
+                                    }
+                                }
+                                if annotations.is_some() {
+                                    if let Some(annotation) = github_annotation(&message.message, &project_rel_path) {
+                                        print(&annotation, false);
+                                    }
+                                }
+                            }
+                            Some(Ok(cargo_metadata::Message::CompilerArtifact(artifact))) => {
+                                let executable = match artifact.executable {
+                                    Some(executable) => executable.to_string_lossy().into_owned(),
+                                    None => return,
+                                };
+                                if artifact.profile.test {
+                                    test_executables.insert(executable);
+                                } else if stream_artifacts && streamed_artifacts.insert(executable.clone()) {
+                                    // The transfer runs alongside the build, it's waited for afterwards.
+                                    let local_file = local_path_of_remote(&executable, &build_path, &sync_root, &project_dir);
+                                    if let Some(parent) = local_file.parent() {
+                                        let _ = std::fs::create_dir_all(parent);
+                                    }
+                                    let mut rsync_artifact = Command::new("rsync");
+                                    rsync_artifact
+                                        .arg("-a")
+                                        .arg("-e")
+                                        .arg(&rsync_shell)
+                                        .arg("--compress")
+                                        .arg(format!("{}:{}", rsync_server, executable))
+                                        .arg(&local_file)
+                                        .stdin(Stdio::null())
+                                        .stdout(Stdio::null());
+                                    info!("Streaming {:?} back", local_file);
+                                    artifact_transfers.push((
+                                        executable,
+                                        thread::spawn(move || rsync_artifact.status().map(|status| status.success())),
+                                    ));
+                                }
+                            }
+                            Some(Ok(cargo_metadata::Message::TextLine(text))) => print(&text, false),
+                            Some(Ok(_)) => {}
+                            _ => print(line, false),
+                        }
+                    })
+                } else {
+                    ssh_build
+                        .stdout(Stdio::inherit())
+                        .stderr(Stdio::inherit())
+                        .status()
+                }
+                .unwrap_or_else(|e| {
+                    error!("Failed to run cargo command remotely (error: {})", e);
+                    exit(-5);
+                });
+                if let Some((stop, watchdog)) = watchdog {
+                    drop(stop);
+                    let _ = watchdog.join();
+                }
+                if connection_failed(status) && servers.peek().is_some() {
+                    warn!("Can't connect to build server '{}', falling back to the next one", build_server);
+                    run_cleanups();
+                    continue 'servers;
+                }
+
+                // A killed remote shell exits with 128 + SIGKILL.
+                if oom_retry && !status.success() && (sigkilled || status.code() == Some(137)) {
+                    let current = variant_jobs.or_else(|| remote_cpu_count(&ssh_options, &build_server));
+                    if let Some(current) = current.filter(|&current| current > 1) {
+                        warn!(
+                            "Build looks killed for lack of memory, retrying with {} jobs instead of {}",
+                            current / 2,
+                            current
+                        );
+                        variant_jobs = Some(current / 2);
+                        attempt -= 1;
+                        continue;
+                    }
+                }
+
+                if status.success() || attempt > rebuild_retries {
+                    break status;
+                }
+                if retry_if_output_matches.is_some() && !output_matched {
+                    info!("The build output doesn't match --retry-if-output-matches, not retrying");
+                    break status;
+                }
+                warn!(
+                    "Build attempt {} failed, retrying ({} of {} retries)",
+                    attempt, attempt, rebuild_retries
+                );
+
+                if resync_between_retries {
+                    info!("Sources are being transferred to your build server again.");
+                    let resync = match &transport_command {
+                        Some(template) => transport(template).status(),
+                        None => {
+                            let mut rsync_again = upload_command();
+                            rsync_again.arg("--info=progress2");
+                            if compress {
+                                rsync_again.arg("--compress");
+                            }
+                            rsync_again.status()
+                        }
+                    };
+                    match resync {
+                        Ok(status) if status.success() => {}
+                        // Retrying on top of a partially synced tree would give misleading results.
+                        Ok(status) => {
+                            error!("Failed to transfer project to build server again ({})", status);
+                            exit(-4);
+                        }
+                        Err(e) => {
+                            error!("Failed to transfer project to build server (error: {})", e);
+                            exit(-4);
+                        }
+                    }
+                }
+            };
+            if let Some((stop, heartbeat)) = heartbeat {
+                drop(stop);
+                let _ = heartbeat.join();
+            }
+            results.push((variant.clone(), status, build_start.elapsed()));
+        }
+
+        for (executable, transfer) in artifact_transfers {
+            if !matches!(transfer.join(), Ok(Ok(true))) {
+                warn!("Failed to stream {} back to the local machine", executable);
+            }
+        }
+
+        if let Some(mut socket) = output_stream {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+        }
+
+        if let Some(record) = &record {
+            let title = redact_env(&format!("cargo {} {}", command, options.join(" ")), &redact);
+            if let Err(e) = write_asciicast(record, &title, record_started_at, &cast_events) {
+                warn!("Failed to write the recording {:?} (error: {})", record, e);
+            } else {
+                info!("Build recorded to {:?}", record);
+            }
+        }
+
+        if variants.len() > 1 {
+            print_matrix_summary(&results, &rustup_default);
+        }
+
+        if let (Some(graph_path), Some(graph_script)) = (&build_graph, &graph_script) {
+            let toolchain = variants[0].toolchain.as_deref().unwrap_or(&rustup_default);
+            // A toolchain file may well select a nightly, only cargo can tell.
+            if !toolchain.starts_with("nightly") && (variants[0].toolchain.is_some() || !toolchain_pinned) {
+                warn!("--build-graph needs a nightly toolchain (--rustup-default nightly), not saving the unit graph");
+            } else {
+                info!("Getting the unit graph from the build server...");
+                let graph = ssh_command(&ssh_options, &build_server)
+                    .arg(graph_script)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output();
+                match graph {
+                    Ok(output) if output.status.success() => match std::fs::write(graph_path, &output.stdout) {
+                        Ok(()) => info!("Unit graph saved to {:?}", graph_path),
+                        Err(e) => warn!("Failed to write the unit graph to {:?} (error: {})", graph_path, e),
+                    },
+                    Ok(output) => warn!("cargo couldn't produce the unit graph ({})", output.status),
+                    Err(e) => warn!("Failed to get the unit graph (error: {})", e),
+                }
+            }
+        }
+
+        // The run fails with the first failing build's status, if any.
+        let build_status = results
+            .iter()
+            .map(|(_, status, _)| *status)
+            .find(|status| !status.success())
+            .unwrap_or(results[results.len() - 1].1);
+        let build_duration = results.iter().map(|(_, _, duration)| *duration).sum();
+        if skip_unchanged {
+            let _ = ssh_command(&ssh_options, &build_server)
+                .arg(format!(
+                    "echo {} > {}",
+                    build_status.code().unwrap_or(1),
+                    shell_escape_path(&last_status_path)
+                ))
+                .stdin(Stdio::null())
+                .status();
+        }
+        update_metrics(|metrics| {
+            metrics.build_duration = Some(build_duration);
+            metrics.success = build_status.success();
+        });
+
+        // A dry build only validates compilation, the artifacts stay on the build server.
+        let (copy_back, copy_back_as, copy_back_sources, copy_back_tests) = if dry_build {
+            if copy_back.is_some() || !copy_back_as.is_empty() || copy_back_sources || copy_back_tests {
+                warn!("Not transferring artifacts back for a --dry-build");
+            }
+            info!(
+                "Dry build {} in {:.1}s",
+                if build_status.success() { "succeeded" } else { "failed" },
+                build_duration.as_secs_f64()
+            );
+            (None, Vec::new(), false, false)
+        } else {
+            (copy_back, copy_back_as, copy_back_sources, copy_back_tests)
+        };
+
+        // The artifacts selected in the config replace the whole target directory of a bare --copy-back.
+        let copy_back_selectors = config_options
+            .iter()
+            .flatten()
+            .map(|config| &config.copy_back)
+            .find(|selectors| !selectors.is_empty())
+            .cloned()
+            .unwrap_or_default();
+        let select_artifacts = matches!(copy_back, Some(None)) && !copy_back_selectors.is_empty();
+        let copy_back = copy_back.filter(|_| !select_artifacts);
+
+        if let Some(build_log) = &build_log {
+            if always_save_log || !build_status.success() {
+                log::info!("Transferring build log to {:?}", build_log);
+                let transferred = Command::new("rsync")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--compress")
+                    .arg(format!("{}:{}", rsync_server, remote_build_log))
+                    .arg(build_log)
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false);
+                if !transferred {
+                    warn!("Failed to transfer the build log back to {:?}", build_log);
+                } else if strip_ansi {
+                    let stripped = std::fs::read_to_string(build_log).map(|log| strip_ansi_escapes(&log));
+                    if let Err(e) = stripped.and_then(|log| std::fs::write(build_log, log)) {
+                        warn!("Failed to strip escape sequences from {:?} (error: {})", build_log, e);
+                    }
+                }
+            }
+        }
+
+        let copy_back_start = Instant::now();
+
+        if copy_back_sources {
+            let sources_back = |dry_run: bool| {
+                let mut rsync_sources = Command::new("rsync");
+                rsync_sources
+                    .arg("-a")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--compress")
+                    .arg("--itemize-changes")
+                    .arg("--exclude")
+                    .arg("target")
+                    .arg("--exclude")
+                    .arg("/.remocom-*");
+                if !hidden {
+                    rsync_sources.arg("--exclude").arg(".*");
+                }
+                if dry_run {
+                    rsync_sources.arg("--dry-run");
+                }
+                rsync_sources
+                    .arg(format!("{}:{}", rsync_server, build_path))
+                    .arg(format!("{}/", sync_root.to_string_lossy()))
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .unwrap_or_else(|e| {
+                        log::error!(
+                            "Failed to transfer sources back to local machine (error: {})",
+                            e
+                        );
+                        exit(-12);
+                    })
+            };
+
+            let proceed = force || {
+                let preview = sources_back(true);
+                let changes = String::from_utf8_lossy(&preview.stdout).into_owned();
+                if changes.trim().is_empty() {
+                    log::info!("No source files were changed remotely");
+                    false
+                } else {
+                    println!("{}", changes.trim_end());
+                    confirm("Overwrite these local files with the remote versions?")
+                }
+            };
+
+            if proceed {
+                log::info!("Transferring changed sources back to client");
+                let transfer = sources_back(false);
+                let changes = String::from_utf8_lossy(&transfer.stdout);
+                for change in changes.lines() {
+                    log::info!("Updated {}", change);
+                }
+            }
+        }
+        if let Some(file_name) = copy_back {
+            log::info!("Transferring artifacts back to client");
+            if variants.iter().any(|variant| variant.target != variants[0].target) {
+                warn!("--copy-back only transfers the artifacts of the first --matrix-target");
+            }
+            let file_name = copy_back_path(&options, &variants[0], &file_name.unwrap_or_default());
+            let local_root = copy_back_root
+                .clone()
+                .unwrap_or_else(|| project_dir.join("target"));
+            if let Err(e) = std::fs::create_dir_all(&local_root) {
+                log::error!("Can't create copy-back directory {:?} (error: {})", local_root, e);
+                exit(-6);
+            }
+            if copy_back_tar {
+                if copy_back_checksum || progress_format == ProgressFormat::Json || link_dest.is_some() {
+                    warn!("--copy-back-checksum, --progress-format and --link-dest don't apply to --copy-back-tar");
+                }
+                let remote_target = format!("{}/target", remote_project_path);
+                let unpacked = copy_back_tarball(
+                    &ssh_options,
+                    &build_server,
+                    &remote_target,
+                    &file_name,
+                    &local_root,
+                    copy_back_timeout.map(Duration::from_secs),
+                )
+                .unwrap_or_else(|e| {
+                    log::error!(
+                        "Failed to transfer target back to local machine (error: {})",
+                        e
+                    );
+                    exit(-6);
+                });
+                match unpacked {
+                    Some(true) => {}
+                    Some(false) => {
+                        log::error!("Failed to transfer target back to local machine");
+                        exit(-6);
+                    }
+                    None => {
+                        log::error!(
+                            "Transferring target back to local machine timed out after {}s",
+                            copy_back_timeout.unwrap_or_default()
+                        );
+                        exit(-10);
+                    }
+                }
+            } else {
+                let mut rsync_back = Command::new("rsync");
+                rsync_back
+                    .arg("-a")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--delete")
+                    .arg("--compress")
+                    .arg("--info=progress2");
+                if copy_back_checksum {
+                    rsync_back.arg("--checksum").arg("--itemize-changes");
+                }
+                if let Some(link_dest) = &link_dest {
+                    // rsync resolves a relative --link-dest from the destination, not from here.
+                    let link_root = std::env::current_dir()
+                        .map(|dir| dir.join(link_dest))
+                        .unwrap_or_else(|_| link_dest.clone());
+                    if !link_root.is_dir() {
+                        warn!("--link-dest {:?} is not a directory, nothing will be hardlinked", link_dest);
+                    }
+                    rsync_back.arg(format!("--link-dest={}/{}", link_root.to_string_lossy(), file_name));
+                }
+                let mut rsync_back = rsync_back
+                    .arg(format!("{}:{}/target/{}", rsync_server, remote_project_path, file_name))
+                    .arg(format!("{}/{}", local_root.to_string_lossy(), file_name))
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .stdin(Stdio::inherit())
+                    .spawn()
+                    .unwrap_or_else(|e| {
+                        log::error!(
+                            "Failed to transfer target back to local machine (error: {})",
+                            e
+                        );
+                        exit(-6);
+                    });
+                let back_stdout = rsync_back.stdout.take().expect("stdout is piped");
+                let back_output = thread::spawn(move || match progress_format {
+                    ProgressFormat::Human => forward_and_capture(back_stdout),
+                    ProgressFormat::Json => capture_progress_json(back_stdout, "copy_back"),
+                });
+
+                let finished = match copy_back_timeout {
+                    Some(seconds) => wait_with_timeout(&mut rsync_back, Duration::from_secs(seconds)),
+                    None => rsync_back.wait().map(Some),
+                }
+                .unwrap_or_else(|e| {
+                    log::error!(
+                        "Failed to transfer target back to local machine (error: {})",
+                        e
+                    );
+                    exit(-6);
+                });
+
+                if finished.is_none() {
+                    log::error!(
+                        "Transferring target back to local machine timed out after {}s",
+                        copy_back_timeout.unwrap_or_default()
+                    );
+                    exit(-10);
+                }
+
+                let back_output = back_output.join().ok().and_then(Result::ok).unwrap_or_default();
+                if copy_back_checksum && count_itemized_changes(&back_output) == 0 {
+                    log::info!("Artifacts unchanged, nothing was transferred");
+                }
+            }
+        }
+
+        if select_artifacts {
+            let listing = ssh_command(&ssh_options, &build_server)
+                .arg(artifact_listing_command(&remote_project_path, &copy_back_selectors))
+                .stdin(Stdio::null())
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                .unwrap_or_else(|e| {
+                    log::error!("Failed to find the artifacts to copy back (error: {})", e);
+                    exit(-6);
+                });
+            let selected: Vec<&str> = listing.lines().filter(|line| !line.is_empty()).collect();
+
+            if selected.is_empty() {
+                warn!("No artifact matches the copy_back patterns of the config");
+            } else {
+                log::info!("Transferring {} selected artifact(s) back to client", selected.len());
+                // `--files-from` keeps the paths relative to the workspace, `-r` copies matched directories whole.
+                let transferred = Command::new("rsync")
+                    .arg("-a")
+                    .arg("-r")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--compress")
+                    .arg("--files-from=-")
+                    .arg(format!("{}:{}/", rsync_server, remote_project_path))
+                    .arg(format!("{}/", project_dir.to_string_lossy()))
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .spawn()
+                    .and_then(|mut child| {
+                        let mut stdin = child.stdin.take().expect("stdin is piped");
+                        for path in &selected {
+                            writeln!(stdin, "{}", path)?;
+                        }
+                        drop(stdin);
+                        child.wait()
+                    })
+                    .map(|status| status.success())
+                    .unwrap_or(false);
+                if !transferred {
+                    log::error!("Failed to transfer the selected artifacts back to local machine");
+                    exit(-6);
+                }
+            }
+        }
+
+        for mapping in &copy_back_as {
+            let remote_file = format!("{}/{}", remote_project_path, mapping.remote);
+            let is_file = ssh_command(&ssh_options, &build_server)
+                .arg(format!("test -f {}", shell_escape_path(&remote_file)))
+                .stdin(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !is_file {
+                log::error!("'{}' is not a file on the build server", mapping.remote);
+                exit(-17);
+            }
+
+            let local_file = project_dir.join(&mapping.local);
+            if let Some(parent) = local_file.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+
+            log::info!("Transferring {} back to {:?}", mapping.remote, local_file);
+            Command::new("rsync")
+                .arg("-a")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg("--compress")
+                .arg("--info=progress2")
+                .arg(format!("{}:{}", rsync_server, remote_file))
+                .arg(&local_file)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit())
+                .output()
+                .unwrap_or_else(|e| {
+                    log::error!(
+                        "Failed to transfer {} back to local machine (error: {})",
+                        mapping.remote,
+                        e
+                    );
+                    exit(-17);
+                });
+        }
+
+        if copy_back_tests {
+            // Without a successful build, a nextest archive may not exist or be left over.
+            if nextest && !build_status.success() {
+                test_executables.clear();
+            }
+            if test_executables.is_empty() {
+                warn!("The build didn't produce any test executable to copy back");
+            }
+            for executable in &test_executables {
+                let local_file = local_path_of_remote(executable, &build_path, &sync_root, &project_dir);
+                if let Some(parent) = local_file.parent() {
+                    let _ = std::fs::create_dir_all(parent);
+                }
+
+                log::info!("Transferring test executable {:?} back", local_file);
+                Command::new("rsync")
+                    .arg("-a")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--compress")
+                    .arg(format!("{}:{}", rsync_server, executable))
+                    .arg(&local_file)
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .stdin(Stdio::inherit())
+                    .output()
+                    .unwrap_or_else(|e| {
+                        log::error!(
+                            "Failed to transfer {} back to local machine (error: {})",
+                            executable,
+                            e
+                        );
+                        exit(-24);
+                    });
+            }
+        }
+
+        if !no_copy_lock {
+            let local_lock = project_dir.join("Cargo.lock");
+            let previous_lock = if diff_lockfile {
+                std::fs::read_to_string(&local_lock).unwrap_or_default()
+            } else {
+                String::new()
+            };
+
+            log::info!("Transferring Cargo.lock file back to the client");
+            Command::new("rsync")
+                .arg("-a")
+                .arg("-e")
+                .arg(&rsync_shell)
+                .arg("--delete")
+                .arg("--compress")
+                .arg("--info=progress2")
+                .arg(format!("{}:{}/Cargo.lock", rsync_server, remote_project_path))
+                .arg(format!("{}/Cargo.lock", project_dir.to_string_lossy()))
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::inherit())
+                .output()
+                .unwrap_or_else(|e| {
+                    log::error!(
+                        "Failed to transfer Cargo.lock back to local machine (error: {})",
+                        e
+                    );
+                    exit(-7);
+                });
+
+            if diff_lockfile {
+                let current_lock = std::fs::read_to_string(&local_lock).unwrap_or_default();
+                let changes = diff_lockfiles(&previous_lock, &current_lock);
+                if changes.is_empty() {
+                    log::info!("Cargo.lock is unchanged");
+                } else {
+                    log::info!("Cargo.lock changed:");
+                    for change in changes {
+                        println!("{}", change);
+                    }
+                }
+            }
+        }
+
+        update_metrics(|metrics| metrics.copy_back_duration = Some(copy_back_start.elapsed()));
+
+        if fingerprint {
+            let commit = match git_dirty_files(&project_dir) {
+                Some(dirty) if dirty.is_empty() => git_output(&project_dir, &["rev-parse", "HEAD"]),
+                _ => None,
+            };
+            let fingerprint_file = xdg::BaseDirectories::with_prefix("remocom").ok().and_then(|base| {
+                let mut hasher = DefaultHasher::new();
+                project_dir.hash(&mut hasher);
+                base.place_cache_file(format!("fingerprints/{}/{}", hasher.finish(), commit.as_ref()?)).ok()
+            });
+            if !build_status.success() {
+                warn!("Not fingerprinting the artifacts, the build didn't succeed");
+            } else if variants.len() > 1 {
+                warn!("Not fingerprinting the artifacts, --fingerprint doesn't support matrix builds");
+            } else if commit.is_none() {
+                warn!("Not fingerprinting the artifacts, the working tree isn't a clean git checkout");
+            } else if let Some(fingerprint_file) = fingerprint_file {
+                let target = build_target(&options, &variants[0]);
+                let artifacts_dir = format!(
+                    "{}/target/{}{}",
+                    remote_project_path,
+                    target.map(|target| format!("{}/", target)).unwrap_or_default(),
+                    profile_dir(&options)
+                );
+                // Only the artifacts themselves, not cargo's dependency info and lock files.
+                let checksums = ssh_command(&ssh_options, &build_server)
+                    .arg(format!(
+                        "cd {} && find . -maxdepth 1 -type f ! -name '.*' ! -name '*.d' -exec sha256sum {{}} + | sort -k 2",
+                        shell_escape_path(&artifacts_dir)
+                    ))
+                    .stdin(Stdio::null())
+                    .output()
+                    .ok()
+                    .filter(|output| output.status.success())
+                    .map(|output| String::from_utf8_lossy(&output.stdout).into_owned());
+                match (checksums, std::fs::read_to_string(&fingerprint_file).ok()) {
+                    (None, _) => warn!("Failed to hash the artifacts in {}", artifacts_dir),
+                    (Some(checksums), Some(previous)) => {
+                        let changed = changed_checksums(&previous, &checksums);
+                        if changed.is_empty() {
+                            info!("Artifacts match the earlier build of this commit");
+                        } else {
+                            warn!("Artifacts differ from an earlier build of this commit, the build isn't reproducible:");
+                            for file in changed {
+                                warn!("  {}", file);
+                            }
+                        }
+                    }
+                    (Some(checksums), None) => match std::fs::write(&fingerprint_file, checksums) {
+                        Ok(()) => info!("Recorded the artifact fingerprint of this commit"),
+                        Err(e) => warn!("Failed to record the fingerprint in {:?} (error: {})", fingerprint_file, e),
+                    },
+                }
+            } else {
+                warn!("Can't create the fingerprint cache file, not fingerprinting the artifacts");
+            }
+        }
+
+        if let Some(deploy) = &deploy {
+            if !build_status.success() || dry_build {
+                warn!("Not deploying, the build didn't succeed or was a --dry-build");
+            } else if variants.len() > 1 {
+                warn!("Not deploying, --deploy doesn't support matrix builds");
+            } else {
+                let binary = deployed_binary(&project_metadata, &options).unwrap_or_else(|e| {
+                    error!("Can't tell which binary to deploy: {}", e);
+                    exit(-26);
+                });
+                let target = build_target(&options, &variants[0]);
+                let remote_binary = format!(
+                    "{}/target/{}{}/{}",
+                    remote_project_path,
+                    target.map(|target| format!("{}/", target)).unwrap_or_default(),
+                    profile_dir(&options),
+                    binary
+                );
+
+                // rsync can't copy between two remote hosts, the binary goes through a local copy.
+                // It keeps its name, for destinations that are directories.
+                let staging_dir = std::env::temp_dir().join(format!("remocom-deploy-{}", std::process::id()));
+                let staged = staging_dir.join(&binary);
+                if let Err(e) = std::fs::create_dir_all(&staging_dir) {
+                    error!("Can't create deploy staging directory {:?} (error: {})", staging_dir, e);
+                    exit(-26);
+                }
+                info!("Deploying {} to {}", binary, deploy);
+                let deployed = Command::new("rsync")
+                    .arg("-a")
+                    .arg("-e")
+                    .arg(&rsync_shell)
+                    .arg("--compress")
+                    .arg(format!("{}:{}", rsync_server, remote_binary))
+                    .arg(&staged)
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false)
+                    && Command::new("rsync")
+                        .arg("-a")
+                        .arg("--compress")
+                        .arg(&staged)
+                        .arg(deploy)
+                        .status()
+                        .map(|status| status.success())
+                        .unwrap_or(false);
+                let _ = std::fs::remove_dir_all(&staging_dir);
+                if !deployed {
+                    error!("Failed to deploy {} to {}", binary, deploy);
+                    exit(-26);
+                }
+                info!("Deployed {} to {}", binary, deploy);
+            }
+        }
+
+        run_cleanups();
+
+        if !build_status.success() {
+            exit(build_status.code().unwrap_or(1))
+        }
+        flush_metrics(0);
+        break;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn build_command<'a>(options: &'a [String], setup: &'a str) -> BuildCommand<'a> {
+        BuildCommand {
+            env: "~/.profile",
+            rustup_command: "",
+            workdir: "~/remote-builds/1/",
+            setup,
+            program: "cargo",
+            command: "build",
+            options,
+            message_format_json: false,
+            target: None,
+            unit_graph: false,
+            prefix: None,
+            build_env: "RUST_BACKTRACE=1",
+            log: None,
+            run_as: None,
+        }
+    }
+
+    #[test]
+    fn unset_env_runs_after_the_profile_and_before_cargo() {
+        let setup = unset_statements(&strings(&["RUSTFLAGS", "CARGO_HOME"]));
+        let options = strings(&["--release"]);
+        let script = build_command(&options, &setup).script(None);
+
+        assert_eq!(
+            script,
+            "source ~/.profile; cd ~/remote-builds/1/; unset RUSTFLAGS; unset CARGO_HOME; \
+             RUST_BACKTRACE=1 cargo build --release"
+        );
+        let source = script.find("source ").unwrap();
+        let unset = script.find("unset RUSTFLAGS").unwrap();
+        let cargo = script.find("cargo build").unwrap();
+        assert!(source < unset && unset < cargo);
+    }
+
+    #[test]
+    fn message_format_goes_before_the_binary_arguments() {
+        let options = strings(&["--release", "--", "--nocapture"]);
+        let mut command = build_command(&options, "");
+        command.command = "test";
+        command.message_format_json = true;
+
+        assert!(command
+            .script(Some(4))
+            .ends_with("cargo test -j 4 --message-format=json --release -- --nocapture"));
+    }
+
+    #[test]
+    fn message_format_of_the_user_is_kept() {
+        let options = strings(&["--message-format=json-diagnostic-rendered-ansi"]);
+        let mut command = build_command(&options, "");
+        command.message_format_json = true;
+
+        assert!(command
+            .script(None)
+            .ends_with("cargo build --message-format=json-diagnostic-rendered-ansi"));
+    }
+
+    #[test]
+    fn matrix_target_goes_before_the_program_arguments() {
+        let options = strings(&["--bin", "app", "--", "arg"]);
+        let mut command = build_command(&options, "");
+        command.command = "run";
+        command.target = Some("aarch64-unknown-linux-gnu");
+
+        assert!(command
+            .script(None)
+            .ends_with("cargo run --target aarch64-unknown-linux-gnu --bin app -- arg"));
+    }
+
+    #[test]
+    fn target_sources_are_relative_to_the_upload_root() {
+        let metadata = cargo_metadata::MetadataCommand::new().no_deps().exec().unwrap();
+
+        assert_eq!(
+            target_source_patterns(&metadata, &metadata.workspace_root),
+            strings(&["/src/main.rs"])
+        );
+        assert_eq!(
+            target_source_patterns(&metadata, metadata.workspace_root.parent().unwrap()),
+            vec![format!("/{}/src/main.rs", metadata.workspace_root.file_name().unwrap().to_string_lossy())]
+        );
+    }
+
+    #[test]
+    fn slugify_sanitizes_package_names() {
+        assert_eq!(slugify("myapp"), "myapp");
+        assert_eq!(slugify("My App"), "my-app");
+        assert_eq!(slugify("my_app-cli"), "my-app-cli");
+        assert_eq!(slugify("  weird!!name@@2  "), "weird-name-2");
+        assert_eq!(slugify("--leading and trailing--"), "leading-and-trailing");
+        assert_eq!(slugify("café"), "caf");
+        assert_eq!(slugify("!!!"), "");
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn expand_alias_replaces_an_alias() {
+        let configs = vec![
+            None,
+            Some(config("[aliases]\nfast = \"me@bigbox\"\narm = \"me@pi\"")),
+        ];
+
+        assert_eq!(expand_alias("fast".to_owned(), &configs), "me@bigbox");
+        assert_eq!(expand_alias("arm".to_owned(), &configs), "me@pi");
+    }
+
+    #[test]
+    fn expand_alias_keeps_other_remotes() {
+        let configs = vec![Some(config("[aliases]\nfast = \"me@bigbox\""))];
+
+        assert_eq!(expand_alias("me@fast".to_owned(), &configs), "me@fast");
+        assert_eq!(expand_alias("bigbox".to_owned(), &configs), "bigbox");
+        assert_eq!(expand_alias("fast".to_owned(), &[None]), "fast");
+    }
+
+    #[test]
+    fn expand_alias_follows_config_precedence() {
+        let configs = vec![
+            Some(config("[aliases]\nfast = \"me@project\"")),
+            Some(config("[aliases]\nfast = \"me@global\"")),
+        ];
+
+        assert_eq!(expand_alias("fast".to_owned(), &configs), "me@project");
+    }
+
+    #[test]
+    fn expand_build_env_fills_in_placeholders() {
+        let expanded = expand_build_env(
+            "OUT_DIR={workspace}/generated CACHE={build_path}cache TARGET={target}",
+            "~/remote-builds/1/",
+            "~/remote-builds/1/app",
+            Some("aarch64-unknown-linux-gnu"),
+        );
+
+        assert_eq!(
+            expanded,
+            "OUT_DIR=~/'remote-builds/1/app'/generated CACHE=~/'remote-builds/1/'cache \
+             TARGET='aarch64-unknown-linux-gnu'"
+        );
+    }
+
+    #[test]
+    fn expand_build_env_escapes_values() {
+        let expanded = expand_build_env("DIR={workspace}", "/srv/it's here/", "/srv/it's here/app", None);
+
+        assert_eq!(expanded, "DIR='/srv/it'\\''s here/app'");
+    }
+
+    #[test]
+    fn expand_build_env_keeps_target_without_one() {
+        assert_eq!(expand_build_env("T={target}", "~/b/", "~/b/", None), "T={target}");
+    }
+
+    #[test]
+    fn validate_remote_accepts_ssh_destinations() {
+        let valid = [
+            "bigbox",
+            "me@bigbox",
+            "me@build.example.com",
+            "me@192.168.1.10",
+            "me@[fe80::1%eth0]",
+            "fe80::1",
+        ];
+        for remote in valid {
+            assert!(validate_remote(remote).is_ok(), "{}", remote);
+        }
+    }
+
+    #[test]
+    fn validate_remote_rejects_local_paths_and_bad_hosts() {
+        let invalid = [
+            "./build",
+            "/tmp/build",
+            "~/build",
+            "build/dir",
+            "C:\\build",
+            "me@",
+            "me@big box",
+            "me@[fe80::1]x",
+        ];
+        for remote in invalid {
+            assert!(validate_remote(remote).is_err(), "{}", remote);
+        }
+    }
+
+    #[test]
+    fn scoped_ipv6_destinations_keep_their_zone() {
+        assert_eq!(ssh_destination("user@[fe80::1%eth0]"), "user@fe80::1%eth0");
+        assert_eq!(rsync_destination("user@[fe80::1%eth0]"), "user@[fe80::1%eth0]");
+        assert_eq!(rsync_destination("user@fe80::1%eth0"), "user@[fe80::1%eth0]");
+        assert_eq!(ssh_destination("[fe80::1%eth0]"), "fe80::1%eth0");
+        assert_eq!(rsync_destination("fe80::1%eth0"), "[fe80::1%eth0]");
+    }
+
+    #[test]
+    fn hostname_destinations_are_unchanged() {
+        assert_eq!(ssh_destination("user@bigbox"), "user@bigbox");
+        assert_eq!(rsync_destination("user@bigbox"), "user@bigbox");
+    }
+
+    #[test]
+    fn inline_build_env_wins_over_the_env_set() {
+        let env_set = strings(&["RUSTFLAGS=-Dwarnings", "CARGO_INCREMENTAL=0"]);
+
+        assert_eq!(
+            merge_env_set(&env_set, "RUSTFLAGS=-Copt-level=1 RUST_LOG=debug"),
+            "CARGO_INCREMENTAL=0 RUSTFLAGS=-Copt-level=1 RUST_LOG=debug"
+        );
+    }
+
+    #[test]
+    fn find_env_set_errors_on_unknown_sets() {
+        let configs = vec![Some(config("[env_sets]\nci = [\"CI=1\"]"))];
+
+        assert_eq!(find_env_set(&configs, "ci"), Ok(&strings(&["CI=1"])[..]));
+        assert_eq!(
+            find_env_set(&configs, "release"),
+            Err("No env set 'release' in the config".to_owned())
+        );
+    }
+
+    fn parse_profiles(toml: &str) -> HashMap<String, Profile> {
+        config(toml).profiles
+    }
+
+    #[test]
+    fn profile_inherits_from_its_base() {
+        let profiles = parse_profiles(
+            "[profiles.base]\n\
+             remote = \"me@base\"\n\
+             build_env = [\"RUSTFLAGS=-Dwarnings\", \"CI=1\"]\n\
+             unset_env = [\"CARGO_HOME\"]\n\
+             [profiles.arm]\n\
+             extends = \"base\"\n\
+             build_env = [\"RUSTFLAGS=-Ctarget-cpu=native\"]\n",
+        );
+        let arm = resolve_profile(&profiles, "arm").unwrap();
+
+        assert_eq!(arm.remote.as_deref(), Some("me@base"));
+        assert_eq!(arm.build_env, strings(&["CI=1", "RUSTFLAGS=-Ctarget-cpu=native"]));
+        assert_eq!(arm.unset_env, strings(&["CARGO_HOME"]));
+    }
+
+    #[test]
+    fn profile_inherits_over_several_levels() {
+        let profiles = parse_profiles(
+            "[profiles.base]\n\
+             remote = \"me@base\"\n\
+             build_env = [\"A=base\", \"B=base\", \"C=base\"]\n\
+             [profiles.arm]\n\
+             extends = \"base\"\n\
+             remote = \"me@arm\"\n\
+             build_env = [\"B=arm\"]\n\
+             [profiles.pi]\n\
+             extends = \"arm\"\n\
+             build_env = [\"C=pi\"]\n",
+        );
+        let pi = resolve_profile(&profiles, "pi").unwrap();
+
+        assert_eq!(pi.remote.as_deref(), Some("me@arm"));
+        assert_eq!(pi.build_env, strings(&["A=base", "B=arm", "C=pi"]));
+    }
+
+    #[test]
+    fn profile_inheritance_cycles_are_errors() {
+        let profiles = parse_profiles(
+            "[profiles.a]\nextends = \"b\"\n[profiles.b]\nextends = \"c\"\n[profiles.c]\nextends = \"a\"\n",
+        );
+
+        assert_eq!(
+            resolve_profile(&profiles, "a").unwrap_err(),
+            "circular profile inheritance: a -> b -> c -> a"
+        );
+        assert!(resolve_profile(&parse_profiles("[profiles.a]\nextends = \"a\"\n"), "a").is_err());
+        assert!(resolve_profile(&parse_profiles("[profiles.a]\nextends = \"missing\"\n"), "a").is_err());
+    }
+
+    /// An empty directory of its own for the test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("remocom-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn merge_toml_merges_tables_and_replaces_values() {
+        let mut base: toml::Value =
+            toml::from_str("remote = \"me@base\"\npool = [\"a\", \"b\"]\n[aliases]\nfast = \"me@fast\"").unwrap();
+        let overrides: toml::Value =
+            toml::from_str("remote = \"me@main\"\npool = [\"c\"]\n[aliases]\narm = \"me@pi\"").unwrap();
+        merge_toml(&mut base, overrides);
+
+        let expected: toml::Value = toml::from_str(
+            "remote = \"me@main\"\npool = [\"c\"]\n[aliases]\nfast = \"me@fast\"\narm = \"me@pi\"",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn config_includes_are_merged() {
+        let dir = test_dir("include");
+        std::fs::create_dir(dir.join("shared")).unwrap();
+        std::fs::write(
+            dir.join("shared/servers.toml"),
+            "remote = \"me@included\"\nfallbacks = [\"me@spare\"]\n[aliases]\nfast = \"me@bigbox\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("shared/profiles.toml"), "[profiles.arm]\nremote = \"me@pi\"\n").unwrap();
+        std::fs::write(
+            dir.join("remocom-config.toml"),
+            "include = [\"shared/servers.toml\", \"shared/profiles.toml\"]\nremote = \"me@main\"\n",
+        )
+        .unwrap();
+
+        let config = read_config(&dir.join("remocom-config.toml")).unwrap();
+        assert_eq!(config.remote.as_deref(), Some("me@main"));
+        assert_eq!(config.fallbacks, strings(&["me@spare"]));
+        assert_eq!(config.aliases.get("fast").map(String::as_str), Some("me@bigbox"));
+        assert_eq!(config.profiles["arm"].remote.as_deref(), Some("me@pi"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn circular_config_includes_are_errors() {
+        let dir = test_dir("include-cycle");
+        std::fs::write(dir.join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        std::fs::write(dir.join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+        std::fs::write(dir.join("self.toml"), "include = [\"self.toml\"]\n").unwrap();
+
+        let error = read_config(&dir.join("a.toml")).unwrap_err();
+        assert!(error.starts_with("circular config include: "), "{}", error);
+        assert!(read_config(&dir.join("self.toml")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn remote_dotfile_skips_comments_and_blank_lines() {
+        let dir = test_dir("dotfile");
+        std::fs::write(dir.join(".remocom-remote"), "# build box\n\n   me@bigbox  \nme@other\n").unwrap();
+
+        assert_eq!(remote_from_dotfile(&dir.join(".remocom-remote")).as_deref(), Some("me@bigbox"));
+        assert_eq!(remote_from_dotfile(&dir.join("missing")), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn remote_dotfile_wins_over_the_config() {
+        let dir = test_dir("dotfile-precedence");
+        let configs = vec![None, Some(config("remote = \"me@config\""))];
+
+        assert_eq!(project_remote(&dir, &configs).as_deref(), Some("me@config"));
+        std::fs::write(dir.join(".remocom-remote"), "me@dotfile\n").unwrap();
+        assert_eq!(project_remote(&dir, &configs).as_deref(), Some("me@dotfile"));
+        std::fs::write(dir.join(".remocom-remote"), "# no remote\n").unwrap();
+        assert_eq!(project_remote(&dir, &configs).as_deref(), Some("me@config"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn toolchain_file_is_detected_under_both_names() {
+        let dir = test_dir("toolchain");
+        std::fs::create_dir(dir.join("app")).unwrap();
+        let app = dir.join("app");
+        assert_eq!(detect_toolchain_file(&app, &dir, None), None);
+
+        std::fs::write(dir.join("rust-toolchain.toml"), "[toolchain]\nchannel = \"stable\"\n").unwrap();
+        assert_eq!(detect_toolchain_file(&app, &dir, None).as_deref(), Some("rust-toolchain.toml"));
+
+        // The closest one wins, whatever its name.
+        std::fs::write(app.join("rust-toolchain"), "nightly\n").unwrap();
+        assert_eq!(detect_toolchain_file(&app, &dir, None).as_deref(), Some("rust-toolchain"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn toolchain_file_name_can_be_overridden() {
+        let dir = test_dir("toolchain-name");
+        std::fs::write(dir.join("rust-toolchain"), "stable\n").unwrap();
+        std::fs::write(dir.join(".toolchain"), "nightly\n").unwrap();
+
+        assert_eq!(detect_toolchain_file(&dir, &dir, Some(".toolchain")).as_deref(), Some(".toolchain"));
+        assert_eq!(detect_toolchain_file(&dir, &dir, Some("rust-toolchain.toml")), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn toolchain_file_outside_the_upload_is_ignored() {
+        let dir = test_dir("toolchain-root");
+        std::fs::create_dir(dir.join("root")).unwrap();
+        std::fs::write(dir.join("rust-toolchain.toml"), "[toolchain]\nchannel = \"stable\"\n").unwrap();
+        let root = dir.join("root");
+
+        assert_eq!(detect_toolchain_file(&root, &root, None), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn copy_back_selectors_are_read_from_the_config() {
+        let selectors = config("copy_back = [\"target/release/myapp\", \"target/release/*.so\"]").copy_back;
+        assert_eq!(selectors, strings(&["target/release/myapp", "target/release/*.so"]));
+        assert!(config("remote = \"me@bigbox\"").copy_back.is_empty());
+        assert!(toml::from_str::<Config>("copy_back = \"target/release/myapp\"").is_err());
+    }
+
+    #[test]
+    fn shell_escape_glob_keeps_only_the_wildcards_active() {
+        assert_eq!(shell_escape_glob("target/release/*.so"), "target/release/*.so");
+        assert_eq!(shell_escape_glob("lib[ab]?.a"), "lib[ab]?.a");
+        assert_eq!(shell_escape_glob("my app's/$HOME;x"), r"my\ app\'s/\$HOME\;x");
+    }
+
+    #[test]
+    fn artifact_selectors_resolve_to_the_matching_files() {
+        let dir = test_dir("selectors");
+        std::fs::create_dir_all(dir.join("target/release/deps")).unwrap();
+        for file in ["myapp", "libfoo.so", "libbar.so", "libbaz.a", "my app.so", "deps/libdep.so"] {
+            std::fs::write(dir.join("target/release").join(file), "").unwrap();
+        }
+        let patterns = strings(&["target/release/myapp", "target/release/*.so", "target/release/missing"]);
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(artifact_listing_command(&dir.to_string_lossy(), &patterns))
+            .output()
+            .unwrap();
+        let listed = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(
+            listed.lines().collect::<Vec<_>>(),
+            [
+                "target/release/myapp",
+                "target/release/libbar.so",
+                "target/release/libfoo.so",
+                "target/release/my app.so",
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn workdir_is_relative_to_the_build_path() {
+        let workdir = parse_workdir("crates/tool/").unwrap();
+        assert_eq!(workdir, "crates/tool");
+
+        let cd = remote_workdir("~/remote-builds/1/", Some(&workdir), "~/remote-builds/1/app");
+        assert_eq!(cd, "~/'remote-builds/1/crates/tool'");
+        let mut command = build_command(&[], "");
+        command.workdir = &cd;
+        assert!(command
+            .script(None)
+            .starts_with("source ~/.profile; cd ~/'remote-builds/1/crates/tool'; "));
+
+        let project = remote_workdir("~/remote-builds/1/", None, "~/remote-builds/1/app");
+        assert_eq!(project, "~/remote-builds/1/app");
+        assert_eq!(remote_workdir("/srv/b/", Some("it's"), "/srv/b/"), "'/srv/b/it'\\''s'");
+    }
+
+    #[test]
+    fn workdir_cant_leave_the_build_path() {
+        assert!(parse_workdir("./tools").is_ok());
+        assert!(parse_workdir("..").is_err());
+        assert!(parse_workdir("crates/../../etc").is_err());
+        assert!(parse_workdir("/etc").is_err());
+    }
+
+    #[test]
+    fn namespaces_get_their_own_build_paths() {
+        let root = Path::new("/home/shared/projects/app");
+        let path = |namespace: &str, dir_naming: DirNaming| {
+            default_build_path(root, namespace, None, dir_naming, "app")
+        };
+
+        assert_ne!(path("alice", DirNaming::Hash), path("bob", DirNaming::Hash));
+        assert_ne!(path("alice", DirNaming::Slug), path("bob", DirNaming::Slug));
+        assert_ne!(path("alice", DirNaming::Hash), path("", DirNaming::Hash));
+        assert_eq!(path("alice", DirNaming::Hash), path("alice", DirNaming::Hash));
+        assert!(path("alice", DirNaming::Slug).starts_with("~/remote-builds/app-"));
+    }
+
+    #[test]
+    fn branches_get_their_own_build_paths() {
+        let root = Path::new("/home/me/app");
+
+        assert_ne!(
+            default_build_path(root, "me", Some("main"), DirNaming::Hash, "app"),
+            default_build_path(root, "me", Some("feature"), DirNaming::Hash, "app")
+        );
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn inplace_is_passed_to_the_upload() {
+        let upload = |inplace| {
+            args(&upload_rsync("ssh", "~/remote-builds/1/", None, false, false, inplace, None))
+        };
+
+        assert!(upload(true).contains(&"--inplace".to_owned()));
+        assert!(!upload(false).contains(&"--inplace".to_owned()));
+    }
+
+    #[test]
+    fn normalize_times_drops_mtimes_and_compares_checksums() {
+        let normalized = args(&upload_rsync("ssh", "~/remote-builds/1/", None, false, true, false, None));
+        assert_eq!(normalized[..2], ["-rlpgoD", "--checksum"]);
+        assert!(!normalized.contains(&"-a".to_owned()));
+
+        let archive = args(&upload_rsync("ssh", "~/remote-builds/1/", None, false, false, false, None));
+        assert_eq!(archive[0], "-a");
+        assert!(!archive.contains(&"--checksum".to_owned()));
+    }
+
+    #[test]
+    fn max_file_size_is_applied_to_the_upload() {
+        let upload = |max_size| {
+            args(&upload_rsync("ssh", "~/remote-builds/1/", None, false, false, false, max_size))
+        };
+
+        let limited = upload(Some(1048576));
+        assert!(limited.contains(&"--max-size=1048576".to_owned()));
+        assert!(limited.contains(&"--info=skip".to_owned()));
+        assert!(!upload(None).iter().any(|arg| arg.starts_with("--max-size")));
+    }
+
+    #[test]
+    fn oversized_files_are_read_from_the_rsync_output() {
+        let output = "sending incremental file list\n\
+                      data/huge.bin is over max-size\n\
+                      \r      32,768  12%    1.00MB/s    0:00:00\rassets/video.mp4 is over max-size\n\
+                      src/main.rs\n";
+
+        assert_eq!(oversized_files(output), ["data/huge.bin", "assets/video.mp4"]);
+        assert!(oversized_files("src/main.rs\n").is_empty());
+    }
+
+    /// Splits an rsync `-e` value into arguments the way rsync does.
+    fn rsync_split(shell: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut chars = shell.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c == ' ' {
+                chars.next();
+                continue;
+            }
+            let mut arg = String::new();
+            let mut quote = None;
+            while let Some(c) = chars.next() {
+                match (quote, c) {
+                    (None, ' ') => break,
+                    (None, '\'' | '"') => quote = Some(c),
+                    (Some(q), c) if c == q && chars.peek() == Some(&q) => {
+                        chars.next();
+                        arg.push(q);
+                    }
+                    (Some(q), c) if c == q => quote = None,
+                    (_, c) => arg.push(c),
+                }
+            }
+            args.push(arg);
+        }
+        args
+    }
+
+    #[test]
+    fn proxy_command_reaches_ssh_through_rsync() {
+        let options = strings(&["-o", "ProxyCommand=socat - UNIX-CONNECT:/run/tunnel.sock"]);
+
+        assert_eq!(
+            rsync_shell(&options),
+            "ssh -o 'ProxyCommand=socat - UNIX-CONNECT:/run/tunnel.sock'"
+        );
+        assert_eq!(rsync_split(&rsync_shell(&options))[1..], options[..]);
+    }
+
+    #[test]
+    fn rsync_shell_quotes_options_with_quotes_and_spaces() {
+        let options = strings(&[
+            "-o",
+            "ProxyCommand=sh -c 'nc %h %p'",
+            "-o",
+            "ProxyCommand=ssh -W \"%h:%p\" it's",
+            "-o",
+            "User='me'",
+            "-o",
+            "BatchMode=yes",
+        ]);
+        let shell = rsync_shell(&options);
+
+        assert_eq!(
+            shell,
+            "ssh -o 'ProxyCommand=sh -c ''nc %h %p''' -o 'ProxyCommand=ssh -W \"%h:%p\" it''s' \
+             -o 'User=''me''' -o BatchMode=yes"
+        );
+        assert_eq!(rsync_split(&shell)[0], "ssh");
+        assert_eq!(rsync_split(&shell)[1..], options[..]);
+    }
+
+    #[test]
+    fn known_hosts_options_pin_the_host_keys() {
+        let known_hosts = Path::new("ci/known_hosts");
+
+        assert_eq!(
+            known_hosts_options(known_hosts, false),
+            ["-o", "UserKnownHostsFile=\"ci/known_hosts\"", "-o", "StrictHostKeyChecking=accept-new"]
+        );
+        assert_eq!(
+            known_hosts_options(known_hosts, true),
+            ["-o", "UserKnownHostsFile=\"ci/known_hosts\"", "-o", "StrictHostKeyChecking=yes"]
+        );
+    }
+
+    /// Splits an ssh option value into words the way ssh does for its file lists: on
+    /// whitespace, honoring quotes and backslash escapes.
+    fn ssh_split(value: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut word = None::<String>;
+        let mut quote = None;
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (_, '\\') => word.get_or_insert_with(String::new).extend(chars.next()),
+                (None, c) if c.is_whitespace() => words.extend(word.take()),
+                (None, '"' | '\'') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (Some(q), c) if c == q => quote = None,
+                (_, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+        words
+    }
+
+    #[test]
+    fn known_hosts_path_with_spaces_stays_one_file() {
+        for path in ["/home/me/build servers/known_hosts", "/tmp/odd \"name\"\\x"] {
+            let options = known_hosts_options(Path::new(path), true);
+            // What ssh receives once rsync has split its `-e` value.
+            let received = rsync_split(&rsync_shell(&options));
+            assert_eq!(received[1..], options[..]);
+
+            let value = received[2].strip_prefix("UserKnownHostsFile=").unwrap();
+            assert_eq!(ssh_split(value), [path]);
+        }
+    }
+
+    #[test]
+    fn gc_removes_the_least_recently_used_directories_first() {
+        let listing = "1700000300 /home/me/remote-builds/recent/\n\
+                       1700000100 /home/me/remote-builds/oldest/\n\
+                       garbage\n\
+                       1700000200 /home/me/remote-builds/my app-a1b2c3/\n";
+
+        assert_eq!(
+            gc_candidates(listing, "current"),
+            [
+                "/home/me/remote-builds/oldest/",
+                "/home/me/remote-builds/my app-a1b2c3/",
+                "/home/me/remote-builds/recent/",
+            ]
+        );
+    }
+
+    #[test]
+    fn gc_keeps_the_current_build_directory() {
+        let listing = "1700000100 /home/me/remote-builds/current/\n1700000200 /home/me/remote-builds/other/\n";
+
+        assert_eq!(gc_candidates(listing, "current"), ["/home/me/remote-builds/other/"]);
+    }
+
+    #[test]
+    fn uploads_mark_the_build_directory_as_used() {
+        assert_eq!(
+            remote_rsync_path("~/remote-builds/1/"),
+            "mkdir -p ~/'remote-builds/1/' && touch ~/'remote-builds/1/'.remocom-last-used && rsync"
+        );
+        let upload = args(&upload_rsync("ssh", "~/remote-builds/1/", None, false, false, false, None));
+        assert!(upload.contains(&remote_rsync_path("~/remote-builds/1/")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn connection_failures_are_told_apart_from_build_failures() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Wait statuses keep the exit code in the second byte.
+        assert!(connection_failed(ExitStatus::from_raw(255 << 8)));
+        assert!(!connection_failed(ExitStatus::from_raw(101 << 8)));
+        assert!(!connection_failed(ExitStatus::from_raw(0)));
+    }
+
+    #[test]
+    fn redact_env_hides_quoted_values_with_spaces() {
+        let redacted = strings(&["API_KEY"]);
+        assert_eq!(
+            redact_env(r#"GITHUB_TOKEN="a b" RUST_LOG=debug  API_KEY='c "d' DB_PASSWORD=e\ f"#, &redacted),
+            "GITHUB_TOKEN=*** RUST_LOG=debug API_KEY=*** DB_PASSWORD=***"
+        );
+        assert_eq!(redact_env("build --features=x", &redacted), "build --features=x");
+    }
+
+    #[test]
+    fn metrics_are_written_once_with_the_exit_code() {
+        let path = test_dir("metrics").join("remocom.prom");
+        *METRICS.lock().unwrap() = Some((path.to_string_lossy().into_owned(), Metrics::default()));
+        update_metrics(|metrics| metrics.upload_bytes = Some(42));
+
+        flush_metrics(-4);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("remocom_upload_bytes 42\n"));
+        assert!(text.contains("remocom_exit_code -4\n"));
+
+        std::fs::remove_file(&path).unwrap();
+        flush_metrics(0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unit_graph_keeps_the_workdir_user_program_and_target() {
+        let options = strings(&["--release"]);
+        let mut command = build_command(&options, "");
+        command.program = "cross";
+        command.target = Some("aarch64-unknown-linux-gnu");
+        command.workdir = "~/remote-builds/1/crates/app";
+        command.run_as = Some("builder");
+        command.unit_graph = true;
+
+        assert_eq!(
+            command.script(None),
+            "source ~/.profile; cd ~/remote-builds/1/crates/app; sudo -u 'builder' -E bash -lc \
+             'RUST_BACKTRACE=1 cross build --target aarch64-unknown-linux-gnu \
+             --unit-graph -Z unstable-options --release'"
+        );
+    }
+
+    #[test]
+    fn copy_back_paths_are_nested_under_the_build_target() {
+        let variant = BuildVariant::default();
+        assert_eq!(copy_back_path(&[], &variant, "release/app"), "release/app");
+
+        let options = strings(&["--release", "--target", "aarch64-unknown-linux-gnu"]);
+        assert_eq!(
+            copy_back_path(&options, &variant, "release/app"),
+            "aarch64-unknown-linux-gnu/release/app"
+        );
+
+        let variant = BuildVariant {
+            toolchain: None,
+            target: Some("x86_64-unknown-linux-musl".to_owned()),
+        };
+        assert_eq!(copy_back_path(&[], &variant, ""), "x86_64-unknown-linux-musl/");
+    }
+
+    #[test]
+    fn preflight_cache_tells_servers_and_connections_apart() {
+        let options = strings(&["-o", "ProxyCommand=ssh -W %h:%p bastion"]);
+        assert_eq!(
+            PreflightCache::file_name("me@host:2222", &options),
+            PreflightCache::file_name("me@host:2222", &options)
+        );
+        assert_ne!(
+            PreflightCache::file_name("me@host:2222", &options),
+            PreflightCache::file_name("me@host-2222", &options)
+        );
+        assert_ne!(
+            PreflightCache::file_name("me@host:2222", &options),
+            PreflightCache::file_name("me@host:2222", &[])
+        );
     }
 }